@@ -0,0 +1,439 @@
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use clap::{Parser, Subcommand};
+use lib_pam_isolate::{
+    Config, Decision, Finding, ListeningSocket, SessionContext, Severity, Usage, accounted_uids,
+    collect_samples, listening_sockets, netns_path, read_audit_log, reapply_sysctls,
+    render_metrics, run_doctor, usage, user_namespaces,
+};
+use log::LevelFilter;
+use nix::unistd::User;
+use serde::Serialize;
+use systemd_journal_logger::JournalLog;
+
+#[derive(Parser, Debug)]
+struct Args {
+    #[arg(long, default_value_os_t = Config::default_path())]
+    config: PathBuf,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List the listening sockets inside of the users' network namespaces.
+    Sockets {
+        /// Only list the sockets of this user.
+        user: Option<String>,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print metrics about the isolated users in the Prometheus text format.
+    Metrics {
+        /// Write them to this file instead, e.g. in node_exporter's textfile collector directory.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Show the namespace lifecycle events of the audit log.
+    Audit {
+        /// Only show the events of this user.
+        #[arg(long)]
+        user: Option<String>,
+        /// Only show events at or after this time, as RFC 3339 timestamp or seconds since the epoch.
+        #[arg(long, value_parser = parse_time)]
+        since: Option<u64>,
+        /// Only show events before this time, as RFC 3339 timestamp or seconds since the epoch.
+        #[arg(long, value_parser = parse_time)]
+        until: Option<u64>,
+        /// Print the matching events as JSON lines, like in the log.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check the prerequisites of the host for the configuration.
+    ///
+    /// Exits with 0 if everything is fine, 1 if there are warnings and 2 if sessions will fail.
+    Doctor {
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check the configuration, including that its sysctls, users and groups exist on this host.
+    ///
+    /// Exits with 1 if there are problems.
+    Validate,
+    /// Print the effective configuration, with the drop-in files merged.
+    Config {
+        /// Also merge the overrides of this user.
+        #[arg(long)]
+        user: Option<String>,
+    },
+    /// Record and report the network traffic of the users.
+    Accounting {
+        #[command(subcommand)]
+        command: AccountingCommand,
+    },
+    /// Manage the sysctls of the users' network namespaces.
+    Sysctl {
+        #[command(subcommand)]
+        command: SysctlCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum SysctlCommand {
+    /// Set the configured sysctls of a user's existing namespace again.
+    ///
+    /// Sessions only set them when the namespace is created. Exits with 1 if some couldn't be set.
+    Reapply {
+        user: String,
+        /// Use the sysctls of this profile, instead of the one the rules select.
+        #[arg(long)]
+        profile: Option<String>,
+        /// The PAM service the rules and profiles are resolved for.
+        #[arg(long, default_value = "isolatectl")]
+        service: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AccountingCommand {
+    /// Record the current counters of all users' interfaces.
+    Collect,
+    /// Report the traffic of the users in a period.
+    Report {
+        /// Only report the traffic of this user.
+        #[arg(long)]
+        user: Option<String>,
+        /// Start of the period, as RFC 3339 timestamp or seconds since the epoch.
+        #[arg(long, value_parser = parse_time)]
+        since: Option<u64>,
+        /// End of the period (exclusive), as RFC 3339 timestamp or seconds since the epoch.
+        #[arg(long, value_parser = parse_time)]
+        until: Option<u64>,
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+fn parse_time(value: &str) -> anyhow::Result<u64> {
+    if let Ok(seconds) = value.parse() {
+        return Ok(seconds);
+    }
+    let time = humantime::parse_rfc3339_weak(value)?;
+    Ok(time.duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+#[derive(Serialize)]
+struct UserSockets {
+    user: String,
+    sockets: Vec<ListeningSocket>,
+}
+
+fn sockets(user: Option<String>, json: bool) -> anyhow::Result<ExitCode> {
+    let namespaces = match user {
+        Some(user) => {
+            let path = netns_path(&user);
+            if !path.exists() {
+                anyhow::bail!("User {user} has no network namespace");
+            }
+            vec![(user, path)]
+        }
+        None => user_namespaces()?,
+    };
+
+    let mut users = Vec::new();
+    let mut failures = Vec::new();
+    for (user, path) in namespaces {
+        match listening_sockets(&path) {
+            Ok(sockets) => users.push(UserSockets { user, sockets }),
+            Err(err) => failures.push(format!("Failed to list the sockets of {user}: {err}")),
+        }
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&users)?);
+    } else {
+        print_sockets(&users);
+    }
+    if failures.is_empty() {
+        return Ok(ExitCode::SUCCESS);
+    }
+    for failure in &failures {
+        eprintln!("{failure}");
+    }
+    Ok(ExitCode::FAILURE)
+}
+
+fn print_sockets(users: &[UserSockets]) {
+    println!(
+        "{:<16} {:<5} {:<40} {:>8} COMMAND",
+        "USER", "PROTO", "ADDRESS", "PID"
+    );
+    for UserSockets { user, sockets } in users {
+        for socket in sockets {
+            let owner = socket.owners.first();
+            println!(
+                "{:<16} {:<5} {:<40} {:>8} {}",
+                user,
+                socket.protocol.as_str(),
+                socket.address.to_string(),
+                owner.map(|owner| owner.pid.to_string()).unwrap_or_default(),
+                owner.map(|owner| owner.command.as_str()).unwrap_or("-"),
+            );
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct UserUsage {
+    user: String,
+    uid: u32,
+    #[serde(flatten)]
+    usage: Usage,
+}
+
+fn accounting(config: &Config, command: AccountingCommand) -> anyhow::Result<()> {
+    let Some(accounting) = &config.accounting else {
+        anyhow::bail!("Accounting is not configured");
+    };
+
+    let (user, since, until, json) = match command {
+        AccountingCommand::Collect => {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            let count = collect_samples(&rt, accounting)?;
+            println!("Recorded {count} samples");
+            return Ok(());
+        }
+        AccountingCommand::Report {
+            user,
+            since,
+            until,
+            json,
+        } => (user, since, until, json),
+    };
+
+    let uids = match user {
+        Some(user) => match User::from_name(&user)? {
+            Some(user) => vec![user.uid],
+            None => anyhow::bail!("Unknown user {user}"),
+        },
+        None => accounted_uids(accounting)?,
+    };
+    let mut users = Vec::new();
+    for uid in uids {
+        let user = User::from_uid(uid)?
+            .map(|user| user.name)
+            .unwrap_or_else(|| uid.to_string());
+        users.push(UserUsage {
+            user,
+            uid: uid.as_raw(),
+            usage: usage(accounting, uid, since, until)?,
+        });
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&users)?);
+        return Ok(());
+    }
+
+    println!(
+        "{:<16} {:>8} {:>16} {:>16} {:>12} {:>12}",
+        "USER", "UID", "RX_BYTES", "TX_BYTES", "RX_PACKETS", "TX_PACKETS"
+    );
+    for UserUsage { user, uid, usage } in &users {
+        println!(
+            "{:<16} {:>8} {:>16} {:>16} {:>12} {:>12}",
+            user, uid, usage.rx_bytes, usage.tx_bytes, usage.rx_packets, usage.tx_packets
+        );
+    }
+    Ok(())
+}
+
+fn metrics(config: &Config, output: Option<PathBuf>) -> anyhow::Result<()> {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let metrics = render_metrics(&rt, config)?;
+    let Some(output) = output else {
+        print!("{metrics}");
+        return Ok(());
+    };
+    // node_exporter must never see a partially written file.
+    let mut tmp = output.clone().into_os_string();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, metrics)?;
+    std::fs::rename(&tmp, &output)?;
+    Ok(())
+}
+
+fn audit(
+    config: &Config,
+    user: Option<String>,
+    since: Option<u64>,
+    until: Option<u64>,
+    json: bool,
+) -> anyhow::Result<()> {
+    let Some(audit) = &config.audit else {
+        anyhow::bail!("The audit log is not configured");
+    };
+    let events = read_audit_log(audit)?.into_iter().filter(|event| {
+        user.as_ref().is_none_or(|user| *user == event.user)
+            && since.is_none_or(|since| event.time >= since)
+            && until.is_none_or(|until| event.time < until)
+    });
+
+    if json {
+        for event in events {
+            println!("{}", serde_json::to_string(&event)?);
+        }
+        return Ok(());
+    }
+
+    println!(
+        "{:<20} {:<13} {:<3} {:<16} {:<16} {:<24} TTY",
+        "TIME", "ACTION", "NS", "USER", "SERVICE", "RHOST"
+    );
+    for event in events {
+        let time = UNIX_EPOCH + Duration::from_secs(event.time);
+        println!(
+            "{:<20} {:<13} {:<3} {:<16} {:<16} {:<24} {}",
+            humantime::format_rfc3339_seconds(time).to_string(),
+            event.action.as_str(),
+            event.namespace.as_str(),
+            event.user,
+            event.service,
+            event.rhost.as_deref().unwrap_or("-"),
+            event.tty.as_deref().unwrap_or("-"),
+        );
+    }
+    Ok(())
+}
+
+fn doctor(config: &Path, json: bool) -> anyhow::Result<ExitCode> {
+    let findings = match Config::load(config) {
+        Ok(config) => {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            run_doctor(&rt, &config)
+        }
+        Err(err) => vec![Finding {
+            check: "config",
+            severity: Severity::Error,
+            message: err.to_string(),
+            hint: None,
+        }],
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&findings)?);
+    } else {
+        for finding in &findings {
+            println!(
+                "{:<7} {:<16} {}",
+                finding.severity.as_str(),
+                finding.check,
+                finding.message
+            );
+            if let Some(hint) = &finding.hint {
+                println!("{:<24} {hint}", "");
+            }
+        }
+    }
+
+    Ok(
+        match findings.iter().map(|finding| finding.severity).max() {
+            Some(Severity::Error) => ExitCode::from(2),
+            Some(Severity::Warning) => ExitCode::from(1),
+            _ => ExitCode::SUCCESS,
+        },
+    )
+}
+
+fn show_config(path: &Path, user: Option<String>) -> anyhow::Result<()> {
+    let config = match &user {
+        Some(user) => Config::load_for_user(path, user)?,
+        None => Config::load(path)?,
+    };
+    for file in Config::files(path, user.as_deref())? {
+        println!("# {}", file.display());
+    }
+    print!("{}", toml::to_string(&config)?);
+    Ok(())
+}
+
+fn validate(config: &Path) -> ExitCode {
+    let problems = Config::validate_files(config);
+    if problems.is_empty() {
+        println!("{config:?} is valid");
+        return ExitCode::SUCCESS;
+    }
+    for problem in &problems {
+        println!("{problem}");
+    }
+    ExitCode::FAILURE
+}
+
+fn sysctl(path: &Path, command: SysctlCommand) -> anyhow::Result<ExitCode> {
+    let SysctlCommand::Reapply {
+        user,
+        profile,
+        service,
+    } = command;
+    let Some(user) = User::from_name(&user)? else {
+        anyhow::bail!("Unknown user {user}");
+    };
+    if !netns_path(&user.name).exists() {
+        anyhow::bail!("User {} has no network namespace", user.name);
+    }
+    let config = Config::load_for_user(path, &user.name)?;
+    // Resolved like for a session, so the profile a rule selects is used.
+    let context = SessionContext::new(&user, &service)?;
+    let profile = match config.decide(&context, profile.as_deref()) {
+        Decision::Skip => anyhow::bail!("Sessions of {} aren't isolated", user.name),
+        Decision::Isolate { profile } => profile,
+    };
+    let isolation = config.isolation(&context, profile.as_deref())?;
+    let failures = reapply_sysctls(&user, &isolation)?;
+    if failures.is_empty() {
+        println!("Reapplied the sysctls of {}", user.name);
+        return Ok(ExitCode::SUCCESS);
+    }
+    for failure in &failures {
+        eprintln!("{failure}");
+    }
+    Ok(ExitCode::FAILURE)
+}
+
+fn main() -> anyhow::Result<ExitCode> {
+    JournalLog::new()
+        .unwrap()
+        .with_extra_fields(vec![("OBJECT_EXE", "isolatectl")])
+        .install()
+        .unwrap();
+    log::set_max_level(LevelFilter::Warn);
+
+    let args = Args::parse();
+
+    match args.command {
+        Command::Doctor { json } => return doctor(&args.config, json),
+        Command::Validate => return Ok(validate(&args.config)),
+        Command::Sysctl { command } => return sysctl(&args.config, command),
+        Command::Sockets { user, json } => return sockets(user, json),
+        Command::Metrics { output } => metrics(&Config::load(&args.config)?, output),
+        Command::Audit {
+            user,
+            since,
+            until,
+            json,
+        } => audit(&Config::load(&args.config)?, user, since, until, json),
+        Command::Config { user } => show_config(&args.config, user),
+        Command::Accounting { command } => accounting(&Config::load(&args.config)?, command),
+    }?;
+    Ok(ExitCode::SUCCESS)
+}
@@ -0,0 +1,247 @@
+use std::{
+    fs::{OpenOptions, read_dir},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures::{StreamExt, stream::TryStreamExt};
+use nix::unistd::Uid;
+use rtnetlink::{new_connection, packet_route::link::LinkAttribute};
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Runtime;
+
+use crate::{Accounting, IoContext, NetlinkContext, Result};
+
+/// The counters of `veth_{uid}_out` at a point in time.
+///
+/// They are seen from the host, so `rx` is the traffic sent by the user and `tx` the traffic
+/// received by the user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sample {
+    /// Seconds since the epoch.
+    pub time: u64,
+    /// Counters start over whenever the interface is re-created, which changes its index.
+    pub ifindex: u32,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_packets: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Usage {
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_packets: u64,
+}
+
+fn sample_path(accounting: &Accounting, uid: Uid) -> PathBuf {
+    accounting.path.join(format!("{uid}.jsonl"))
+}
+
+/// Reads the counters of an interface in the current network namespace.
+pub(crate) async fn read_sample(handle: &rtnetlink::Handle, name: &str) -> Result<Option<Sample>> {
+    let mut links = handle.link().get().match_name(name.to_owned()).execute();
+    let Some(link) = links.try_next().await.netlink("get", name)? else {
+        return Ok(None);
+    };
+    links.collect::<Vec<_>>().await; // drain stream
+
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    Ok(link
+        .attributes
+        .iter()
+        .find_map(|attribute| match attribute {
+            LinkAttribute::Stats64(stats) => Some(Sample {
+                time,
+                ifindex: link.header.index,
+                rx_bytes: stats.rx_bytes,
+                tx_bytes: stats.tx_bytes,
+                rx_packets: stats.rx_packets,
+                tx_packets: stats.tx_packets,
+            }),
+            _ => None,
+        }))
+}
+
+/// Appends a sample to the store of a user.
+pub fn record_sample(accounting: &Accounting, uid: Uid, sample: &Sample) -> Result<()> {
+    std::fs::create_dir_all(&accounting.path).io("create", &accounting.path)?;
+    let path = sample_path(accounting, uid);
+    let mut line = serde_json::to_vec(sample).io("serialize a sample for", &path)?;
+    line.push(b'\n');
+    // A single write to a file opened with O_APPEND doesn't interleave with other writers.
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(&line))
+        .io("append to", &path)
+}
+
+/// Records the counters of all `veth_{uid}_out` interfaces, returning the number of samples.
+///
+/// This should run periodically, e.g. from a timer, as traffic since the last sample is lost
+/// when an interface is deleted by anything else than pam_isolate.
+pub fn collect_samples(rt: &Runtime, accounting: &Accounting) -> Result<usize> {
+    rt.block_on(async {
+        let (connection, handle, _) = new_connection().syscall("connect to netlink")?;
+        tokio::spawn(connection);
+
+        let mut names = Vec::new();
+        let mut links = handle.link().get().execute();
+        while let Some(link) = links.try_next().await.netlink("get", "all links")? {
+            names.extend(
+                link.attributes
+                    .iter()
+                    .find_map(|attribute| match attribute {
+                        LinkAttribute::IfName(name) => Some(name.clone()),
+                        _ => None,
+                    }),
+            );
+        }
+
+        let mut count = 0;
+        for name in names {
+            let Some(uid) = name
+                .strip_prefix("veth_")
+                .and_then(|name| name.strip_suffix("_out"))
+                .and_then(|uid| uid.parse().ok())
+                .map(Uid::from_raw)
+            else {
+                continue;
+            };
+            if let Some(sample) = read_sample(&handle, &name).await? {
+                record_sample(accounting, uid, &sample)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    })
+}
+
+/// Lists the UIDs with recorded samples.
+pub fn accounted_uids(accounting: &Accounting) -> Result<Vec<Uid>> {
+    if !accounting.path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut uids = read_dir(&accounting.path)
+        .io("read", &accounting.path)?
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name();
+            let uid = Path::new(&name).file_stem()?.to_str()?.parse().ok()?;
+            Some(Uid::from_raw(uid))
+        })
+        .collect::<Vec<_>>();
+    uids.sort_by_key(|uid| uid.as_raw());
+    Ok(uids)
+}
+
+/// Sums up the traffic of a user between two points in time, in seconds since the epoch.
+///
+/// Traffic is attributed to the time of the sample it was first seen in.
+pub fn usage(
+    accounting: &Accounting,
+    uid: Uid,
+    since: Option<u64>,
+    until: Option<u64>,
+) -> Result<Usage> {
+    let path = sample_path(accounting, uid);
+    if !path.exists() {
+        return Ok(Usage::default());
+    }
+
+    let file = std::fs::File::open(&path).io("open", &path)?;
+    let mut samples = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.io("read", &path)?;
+        samples.push(serde_json::from_str(&line).io("parse", &path)?);
+    }
+    Ok(sum_usage(&samples, since, until))
+}
+
+/// Sums up the traffic between the samples, see [`usage`].
+fn sum_usage(samples: &[Sample], since: Option<u64>, until: Option<u64>) -> Usage {
+    let mut usage = Usage::default();
+    let mut previous: Option<&Sample> = None;
+    for sample in samples {
+        let delta = match &previous {
+            Some(previous)
+                if previous.ifindex == sample.ifindex
+                    && previous.rx_bytes <= sample.rx_bytes
+                    && previous.tx_bytes <= sample.tx_bytes =>
+            {
+                Usage {
+                    rx_bytes: sample.rx_bytes - previous.rx_bytes,
+                    tx_bytes: sample.tx_bytes - previous.tx_bytes,
+                    rx_packets: sample.rx_packets.saturating_sub(previous.rx_packets),
+                    tx_packets: sample.tx_packets.saturating_sub(previous.tx_packets),
+                }
+            }
+            // The interface was re-created, so its counters started from zero.
+            _ => Usage {
+                rx_bytes: sample.rx_bytes,
+                tx_bytes: sample.tx_bytes,
+                rx_packets: sample.rx_packets,
+                tx_packets: sample.tx_packets,
+            },
+        };
+        if since.is_none_or(|since| sample.time >= since)
+            && until.is_none_or(|until| sample.time < until)
+        {
+            usage.rx_bytes += delta.rx_bytes;
+            usage.tx_bytes += delta.tx_bytes;
+            usage.rx_packets += delta.rx_packets;
+            usage.tx_packets += delta.tx_packets;
+        }
+        previous = Some(sample);
+    }
+    usage
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(time: u64, ifindex: u32, rx_bytes: u64, tx_bytes: u64) -> Sample {
+        Sample {
+            time,
+            ifindex,
+            rx_bytes,
+            tx_bytes,
+            rx_packets: rx_bytes / 100,
+            tx_packets: tx_bytes / 100,
+        }
+    }
+
+    #[test]
+    fn usage_across_a_new_interface() {
+        let samples = [
+            sample(10, 5, 1000, 2000),
+            sample(20, 5, 1500, 2600),
+            // Re-created, the counters started over.
+            sample(30, 7, 300, 400),
+            sample(40, 7, 800, 500),
+        ];
+        let usage = sum_usage(&samples, None, None);
+        assert_eq!(
+            (
+                usage.rx_bytes,
+                usage.tx_bytes,
+                usage.rx_packets,
+                usage.tx_packets
+            ),
+            (2300, 3100, 23, 31)
+        );
+
+        // Traffic counts at the time of the sample it was first seen in.
+        let usage = sum_usage(&samples, Some(20), Some(40));
+        assert_eq!((usage.rx_bytes, usage.tx_bytes), (800, 1000));
+    }
+}
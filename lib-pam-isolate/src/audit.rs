@@ -0,0 +1,128 @@
+use std::{
+    fs::OpenOptions,
+    io::{BufRead, BufReader, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Audit, IoContext, Result, SessionContext};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditAction {
+    Created,
+    Joined,
+    Repaired,
+    /// The veth pair left behind by a namespace which is gone was removed.
+    #[serde(rename = "stale_cleanup")]
+    StaleCleanup,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Created => "created",
+            AuditAction::Joined => "joined",
+            AuditAction::Repaired => "repaired",
+            AuditAction::StaleCleanup => "stale_cleanup",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NamespaceKind {
+    Net,
+    Mnt,
+}
+
+impl NamespaceKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NamespaceKind::Net => "net",
+            NamespaceKind::Mnt => "mnt",
+        }
+    }
+}
+
+/// A line of the audit log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    /// Seconds since the epoch.
+    pub time: u64,
+    pub action: AuditAction,
+    pub namespace: NamespaceKind,
+    pub user: String,
+    pub uid: u32,
+    /// The PAM service, or `"wrapns"`.
+    pub service: String,
+    pub rhost: Option<String>,
+    pub tty: Option<String>,
+    pub pid: u32,
+}
+
+fn append_event(audit: &Audit, event: &AuditEvent) -> Result<()> {
+    if let Some(parent) = audit.path.parent() {
+        std::fs::create_dir_all(parent).io("create", parent)?;
+    }
+    let mut line = serde_json::to_vec(event).io("serialize an event for", &audit.path)?;
+    line.push(b'\n');
+    // Opened for every event, so a rotated log is never written to.
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&audit.path)
+        .and_then(|mut file| file.write_all(&line))
+        .io("append to", &audit.path)
+}
+
+/// Records a namespace lifecycle event of a session, if the audit log is enabled.
+///
+/// Errors are logged but don't fail the session.
+pub(crate) fn audit(
+    audit: Option<&Audit>,
+    session: &SessionContext,
+    action: AuditAction,
+    namespace: NamespaceKind,
+) {
+    let Some(audit) = audit else {
+        return;
+    };
+    let event = AuditEvent {
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or_default(),
+        action,
+        namespace,
+        user: session.username.clone(),
+        uid: session.uid.as_raw(),
+        service: session.service.clone(),
+        rhost: session.rhost.clone(),
+        tty: session.tty.clone(),
+        pid: std::process::id(),
+    };
+    if let Err(err) = append_event(audit, &event) {
+        log::error!(
+            "[pam_isolate] Failed to write to the audit log {:?}: {err:?}",
+            audit.path
+        );
+    }
+}
+
+/// Reads all events of the audit log, skipping malformed lines.
+pub fn read_audit_log(audit: &Audit) -> Result<Vec<AuditEvent>> {
+    if !audit.path.exists() {
+        return Ok(Vec::new());
+    }
+    let file = std::fs::File::open(&audit.path).io("open", &audit.path)?;
+    let mut events = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        match serde_json::from_str(&line.io("read", &audit.path)?) {
+            Ok(event) => events.push(event),
+            Err(err) => log::warn!("Skipping line {} of {:?}: {err}", index + 1, audit.path),
+        }
+    }
+    Ok(events)
+}
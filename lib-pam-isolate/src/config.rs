@@ -1,31 +1,583 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    net::{Ipv4Addr, SocketAddr},
+    ops::RangeInclusive,
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
+use ipnet::IpNet;
 use log::LevelFilter;
-use serde::Deserialize;
+use serde::{
+    Deserialize, Deserializer, Serialize,
+    de::{self, MapAccess, SeqAccess, Visitor},
+};
+
+use crate::{Error, Result, SessionContext, rules::in_any_group};
 
-#[derive(Debug, Default, Deserialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Users {
     #[serde(default)]
     pub ignore: Vec<String>,
 }
 
-#[derive(Debug, Default, Deserialize)]
+/// Host sockets made available in the user's mount namespace, read-only.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SocketBind {
+    /// The directory containing the sockets on the host.
+    pub source: PathBuf,
+    /// Where the directory shows up in the namespace, `source` by default.
+    pub target: Option<PathBuf>,
+    /// Names of the sockets to bind. The whole directory is bound if empty.
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Mount {
     #[serde(default)]
     pub tmp: String,
     #[serde(default)]
     pub size: String,
+    #[serde(default)]
+    pub sockets: Vec<SocketBind>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        }
+    }
+}
+
+/// Forwards connections to a host address to a port inside of a user's namespace.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PortForward {
+    pub user: String,
+    /// The host address and port to forward. IPv6 addresses are forwarded to the IPv6 address inside.
+    pub listen: SocketAddr,
+    /// The port inside of the namespace.
+    pub port: u16,
+    #[serde(default)]
+    pub protocol: Protocol,
 }
 
-#[derive(Debug, Default, Deserialize)]
+/// A service on the host made reachable from inside of the namespaces.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostService {
+    /// The port on the address of `veth_{uid}_out`, as seen from inside of the namespace.
+    pub port: u16,
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// Where the service listens on the host, `127.0.0.1:<port>` by default.
+    #[serde(default, deserialize_with = "deserialize_host_service_target")]
+    pub target: Option<SocketAddr>,
+}
+
+fn deserialize_host_service_target<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<SocketAddr>, D::Error> {
+    let target = Option::<SocketAddr>::deserialize(deserializer)?;
+    // Forwarding to the host's loopback relies on `route_localnet`, which only exists for IPv4.
+    if let Some(target) = target
+        && target.is_ipv6()
+        && target.ip().is_loopback()
+    {
+        return Err(de::Error::custom(format!(
+            "{target} can't be forwarded to, listen on 127.0.0.1 or another address instead"
+        )));
+    }
+    Ok(target)
+}
+
+impl HostService {
+    pub fn target(&self) -> SocketAddr {
+        self.target
+            .unwrap_or(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), self.port))
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Net {
     pub loopback: String,
+    #[serde(default)]
+    pub port_forwards: Vec<PortForward>,
+    #[serde(default)]
+    pub host_services: HashMap<String, HostService>,
+    /// Abstract sockets on the host to proxy into the namespaces, without the leading `@`.
+    #[serde(default)]
+    pub abstract_sockets: Vec<String>,
+}
+
+/// Where the traffic counters of the users are stored.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Accounting {
+    #[serde(default = "default_accounting_path")]
+    pub path: PathBuf,
+}
+
+/// Where namespace lifecycle events are logged.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Audit {
+    #[serde(default = "default_audit_path")]
+    pub path: PathBuf,
+}
+
+/// Names of the environment variables describing the session. Unset ones aren't exported.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Env {
+    pub inside_ipv4: Option<String>,
+    pub inside_ipv6: Option<String>,
+    pub outside_ipv4: Option<String>,
+    pub outside_ipv6: Option<String>,
+    pub netns: Option<String>,
+    pub netns_path: Option<String>,
+}
+
+impl Env {
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        [
+            &self.inside_ipv4,
+            &self.inside_ipv6,
+            &self.outside_ipv4,
+            &self.outside_ipv6,
+            &self.netns,
+            &self.netns_path,
+        ]
+        .into_iter()
+        .flatten()
+        .map(String::as_str)
+    }
+}
+
+/// The value of a sysctl, written to `/proc/sys` as text.
+///
+/// In the config, this is a string, an integer, an array of those for sysctls taking multiple
+/// values, or a table `{ type = "u8", value = 1 }` checking the range of the integer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum SysctlValue {
+    String(String),
+    Integer(i64),
+    /// Written separated by tabs, e.g. `[0, 2147483647]` for `net.ipv4.ping_group_range`.
+    Array(Vec<SysctlValue>),
+}
+
+impl fmt::Display for SysctlValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SysctlValue::String(value) => f.write_str(value),
+            SysctlValue::Integer(value) => write!(f, "{value}"),
+            SysctlValue::Array(values) => {
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {
+                        f.write_str("\t")?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The range of the integer types of typed sysctl values, named like in the `sysctl` crate.
+fn integer_range(ty: &str) -> Option<RangeInclusive<i64>> {
+    Some(match ty {
+        "u8" => 0..=u8::MAX.into(),
+        "u16" => 0..=u16::MAX.into(),
+        "u32" | "uint" => 0..=u32::MAX.into(),
+        // TOML has no larger integers.
+        "u64" | "ulong" => 0..=i64::MAX,
+        "s8" => i8::MIN.into()..=i8::MAX.into(),
+        "s16" => i16::MIN.into()..=i16::MAX.into(),
+        "s32" | "int" => i32::MIN.into()..=i32::MAX.into(),
+        "s64" | "long" => i64::MIN..=i64::MAX,
+        _ => return None,
+    })
+}
+
+struct SysctlValueVisitor;
+
+impl<'de> Visitor<'de> for SysctlValueVisitor {
+    type Value = SysctlValue;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a string, an integer, an array of those, or a table with `type` and `value`")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> std::result::Result<SysctlValue, E> {
+        Ok(SysctlValue::String(value.to_owned()))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> std::result::Result<SysctlValue, E> {
+        Ok(SysctlValue::Integer(value))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(
+        self,
+        mut seq: A,
+    ) -> std::result::Result<SysctlValue, A::Error> {
+        let mut values = Vec::new();
+        while let Some(value) = seq.next_element()? {
+            if let SysctlValue::Array(_) = value {
+                return Err(de::Error::custom("sysctl values can't be nested arrays"));
+            }
+            values.push(value);
+        }
+        Ok(SysctlValue::Array(values))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(
+        self,
+        mut map: A,
+    ) -> std::result::Result<SysctlValue, A::Error> {
+        let (mut ty, mut value) = (None::<String>, None::<i64>);
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "type" => ty = Some(map.next_value()?),
+                "value" => value = Some(map.next_value()?),
+                _ => return Err(de::Error::unknown_field(&key, &["type", "value"])),
+            }
+        }
+        let ty = ty.ok_or_else(|| de::Error::missing_field("type"))?;
+        let value = value.ok_or_else(|| de::Error::missing_field("value"))?;
+        let Some(range) = integer_range(&ty) else {
+            return Err(de::Error::custom(format!("unknown sysctl type {ty:?}")));
+        };
+        if !range.contains(&value) {
+            return Err(de::Error::custom(format!(
+                "{value} is out of the range of {ty}, {range:?}"
+            )));
+        }
+        Ok(SysctlValue::Integer(value))
+    }
+}
+
+impl<'de> Deserialize<'de> for SysctlValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_any(SysctlValueVisitor)
+    }
+}
+
+/// Only `net.*` sysctls are namespaced, anything else would be set on the host.
+fn check_netns_sysctls<E: de::Error>(
+    sysctls: &HashMap<String, SysctlValue>,
+) -> std::result::Result<(), E> {
+    match sysctls.keys().find(|key| !key.starts_with("net.")) {
+        Some(key) => Err(E::custom(format!(
+            "sysctl {key:?} isn't namespaced and would be set on the host, only net.* keys are"
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn check_host_iface_sysctls<E: de::Error>(
+    sysctls: &HashMap<String, SysctlValue>,
+) -> std::result::Result<(), E> {
+    for key in sysctls.keys() {
+        let name = key
+            .strip_prefix("ipv4.")
+            .or_else(|| key.strip_prefix("ipv6."));
+        if name.is_none_or(|name| name.is_empty() || name.contains(['.', '/'])) {
+            return Err(de::Error::custom(format!(
+                "invalid interface sysctl {key:?}, use e.g. \"ipv4.rp_filter\""
+            )));
+        }
+    }
+    Ok(())
+}
+
+const SYSCTL_FIELDS: &[&str] = &["netns", "host_iface", "groups", "users"];
+
+struct SysctlConfigVisitor;
+
+impl<'de> Visitor<'de> for SysctlConfigVisitor {
+    type Value = SysctlConfig;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a table of sysctl sections")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(
+        self,
+        mut map: A,
+    ) -> std::result::Result<SysctlConfig, A::Error> {
+        let mut config = SysctlConfig::default();
+        let mut legacy = HashMap::new();
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "netns" => config.netns = map.next_value()?,
+                "host_iface" => config.host_iface = map.next_value()?,
+                "groups" => config.groups = map.next_value()?,
+                "users" => config.users = map.next_value()?,
+                // Before `[sysctl.netns]`, the sysctls were given in `[sysctl]` directly.
+                key if key.starts_with("net.") => {
+                    legacy.insert(key.to_owned(), map.next_value()?);
+                }
+                _ => return Err(de::Error::unknown_field(&key, SYSCTL_FIELDS)),
+            }
+        }
+        check_netns_sysctls(&config.netns)?;
+        check_host_iface_sysctls(&config.host_iface)?;
+        for sysctls in config.groups.values().chain(config.users.values()) {
+            check_netns_sysctls(sysctls)?;
+        }
+        if !legacy.is_empty() {
+            let mut keys = legacy.keys().cloned().collect::<Vec<_>>();
+            keys.sort();
+            log::warn!(
+                "[pam_isolate] Sysctls directly in [sysctl] are deprecated, move {} to [sysctl.netns]",
+                keys.join(", ")
+            );
+            for (key, value) in legacy {
+                config.netns.entry(key).or_insert(value);
+            }
+            config.legacy_keys = keys;
+        }
+        Ok(config)
+    }
+}
+
+impl<'de> Deserialize<'de> for SysctlConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_map(SysctlConfigVisitor)
+    }
+}
+
+/// Sysctls applied when a user's network namespace is created.
+///
+/// `net.*` keys directly in `[sysctl]` are still accepted as part of `netns`, with a warning.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SysctlConfig {
+    /// Set inside of the namespace, only `net.*` keys are namespaced.
+    pub netns: HashMap<String, SysctlValue>,
+    /// Set on `veth_{uid}_out` on the host, as `ipv4.<name>` or `ipv6.<name>` for
+    /// `net.ipv4.conf.<interface>.<name>`.
+    pub host_iface: HashMap<String, SysctlValue>,
+    /// Merged on top of `netns` for the members of each group, in the order of the names.
+    pub groups: BTreeMap<String, HashMap<String, SysctlValue>>,
+    /// Merged on top of `netns` and `groups` for each user.
+    pub users: BTreeMap<String, HashMap<String, SysctlValue>>,
+    /// The keys given directly in `[sysctl]`, which were moved to `netns`.
+    #[serde(skip)]
+    pub legacy_keys: Vec<String>,
+}
+
+impl SysctlConfig {
+    pub fn is_empty(&self) -> bool {
+        self.netns.is_empty()
+            && self.host_iface.is_empty()
+            && self.groups.is_empty()
+            && self.users.is_empty()
+    }
+
+    /// Merges the overrides of the user and its groups into `netns`, leaving them empty.
+    pub fn for_user(&self, context: &SessionContext) -> SysctlConfig {
+        let mut netns = self.netns.clone();
+        for (group, sysctls) in &self.groups {
+            if in_any_group(std::slice::from_ref(group), &context.groups) {
+                netns.extend(sysctls.clone());
+            }
+        }
+        if let Some(sysctls) = self.users.get(&context.username) {
+            netns.extend(sysctls.clone());
+        }
+        SysctlConfig {
+            netns,
+            host_iface: self.host_iface.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// The `[sysctl.host_iface]` entries with their full keys for the given interface.
+    pub fn host_iface_sysctls(&self, interface: &str) -> HashMap<String, SysctlValue> {
+        self.host_iface
+            .iter()
+            .filter_map(|(key, value)| {
+                let (family, name) = key.split_once('.')?;
+                Some((
+                    format!("net.{family}.conf.{interface}.{name}"),
+                    value.clone(),
+                ))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    Isolate,
+    Skip,
 }
 
-#[derive(Debug, Deserialize)]
+/// An inclusive range of UIDs.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UidRange {
+    pub min: u32,
+    pub max: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub action: RuleAction,
+    /// The profile to use when isolating, see [`Config::decide`].
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// Matches the primary as well as supplementary groups.
+    #[serde(default)]
+    pub groups: Vec<String>,
+    pub uids: Option<UidRange>,
+    #[serde(default)]
+    pub services: Vec<String>,
+    /// Networks `PAM_RHOST` has to be in. Never matches host names.
+    #[serde(default)]
+    pub rhosts: Vec<IpNet>,
+    /// Values of `PAM_TTY`, a trailing `*` matches any suffix.
+    #[serde(default)]
+    pub ttys: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailureAction {
+    /// Fail the session with an error code.
+    #[default]
+    Deny,
+    /// Open the session without (complete) isolation and log an error.
+    Allow,
+}
+
+impl FromStr for FailureAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deny" => Ok(FailureAction::Deny),
+            "allow" => Ok(FailureAction::Allow),
+            _ => Err(format!(
+                "Unknown failure action {s:?}, use \"deny\" or \"allow\""
+            )),
+        }
+    }
+}
+
+/// What to do when setting up the isolation fails.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FailurePolicy {
+    #[serde(default)]
+    pub action: FailureAction,
+    /// Members of these groups are allowed in even if `action` is `deny`.
+    #[serde(default)]
+    pub allow_groups: Vec<String>,
+}
+
+/// The namespaces, mounts and sysctls of a group of sessions.
+///
+/// There are no resource limits (yet), as pam_isolate doesn't manage cgroups.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// PAM services this profile is used for, unless another one is passed via `--profile`.
+    #[serde(default)]
+    pub services: Vec<String>,
+    #[serde(default = "default_true")]
+    pub netns: bool,
+    #[serde(default = "default_true")]
+    pub mntns: bool,
+    /// Overrides the global `[mount]` section.
+    pub mount: Option<Mount>,
+    /// Overrides the global `[sysctl]` section.
+    pub sysctl: Option<SysctlConfig>,
+    /// Overrides the policy passed to the PAM module.
+    pub on_failure: Option<FailurePolicy>,
+}
+
+/// The isolation settings that apply to a single session, with the profile applied.
+#[derive(Debug, Clone)]
+pub struct Isolation {
+    pub profile: Option<String>,
+    pub netns: bool,
+    /// `None` if no mount namespace should be set up.
+    pub mount: Option<Mount>,
+    /// Always empty without a network namespace, as those would end up on the host otherwise.
+    /// The overrides of the user and its groups are already merged into `netns`.
+    pub sysctl: SysctlConfig,
+    /// The port forwards of the user, empty without a network namespace.
+    pub port_forwards: Vec<PortForward>,
+    /// Sorted by name, empty without a network namespace.
+    pub host_services: Vec<HostService>,
+    /// Empty without a network namespace.
+    pub abstract_sockets: Vec<String>,
+    /// `None` without a network namespace.
+    pub accounting: Option<Accounting>,
+    pub audit: Option<Audit>,
+    pub on_failure: Option<FailurePolicy>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserManager {
+    /// `Some(false)` skips sessions of the `service`, `None` isolates them if `pam_isolate.so` is
+    /// in its stack, without using the user manager as a template for the mount namespace.
+    pub isolate: Option<bool>,
+    #[serde(default = "default_user_manager_service")]
+    pub service: String,
+    #[serde(default = "default_user_manager_exe")]
+    pub exe: PathBuf,
+}
+
+impl Default for UserManager {
+    fn default() -> Self {
+        UserManager {
+            isolate: None,
+            service: default_user_manager_service(),
+            exe: default_user_manager_exe(),
+        }
+    }
+}
+
+impl UserManager {
+    /// The executable whose processes must not be used as a template for the mount namespace.
+    ///
+    /// Unless the user manager is isolated, it might run outside of the user's namespaces.
+    pub fn ignored_exe(&self) -> Option<&Path> {
+        (self.isolate != Some(true)).then_some(self.exe.as_path())
+    }
+
+    /// Whether sessions of the user manager's service are left alone.
+    pub fn skipped(&self) -> bool {
+        self.isolate == Some(false)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     #[serde(default)]
     pub users: Users,
@@ -34,9 +586,21 @@ pub struct Config {
     pub mount: Option<Mount>,
     #[serde(default = "default_user_env")]
     pub user_env: String,
+    #[serde(default)]
+    pub env: Env,
+    /// Sent to the user on login, see [`crate::SessionInfo::render_message`] for placeholders.
+    pub login_message: Option<String>,
     pub net: Net,
     #[serde(default)]
-    pub sysctl: HashMap<String, toml::Value>,
+    pub sysctl: SysctlConfig,
+    #[serde(default)]
+    pub user_manager: UserManager,
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    pub accounting: Option<Accounting>,
+    pub audit: Option<Audit>,
 }
 
 impl Default for Config {
@@ -46,10 +610,20 @@ impl Default for Config {
             log_level: default_log_level(),
             mount: None,
             user_env: default_user_env(),
+            env: Default::default(),
+            login_message: None,
             net: Net {
                 loopback: "lo".to_owned(),
+                port_forwards: Vec::default(),
+                host_services: HashMap::default(),
+                abstract_sockets: Vec::default(),
             },
-            sysctl: HashMap::default(),
+            sysctl: SysctlConfig::default(),
+            user_manager: Default::default(),
+            profiles: HashMap::default(),
+            rules: Vec::default(),
+            accounting: None,
+            audit: None,
         }
     }
 }
@@ -62,13 +636,373 @@ fn default_user_env() -> String {
     "PAM_NETNS_USER".to_owned()
 }
 
+fn default_accounting_path() -> PathBuf {
+    ["/", "var", "lib", "pam_isolate", "accounting"]
+        .iter()
+        .collect()
+}
+
+fn default_audit_path() -> PathBuf {
+    ["/", "var", "log", "pam_isolate", "audit.jsonl"]
+        .iter()
+        .collect()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_user_manager_service() -> String {
+    "systemd-user".to_owned()
+}
+
+fn default_user_manager_exe() -> PathBuf {
+    ["/", "usr", "lib", "systemd", "systemd"].iter().collect()
+}
+
+/// Arrays which later files add to, instead of replacing them.
+const APPENDED_ARRAYS: &[&str] = &["net.port_forwards", "rules", "users.ignore"];
+
+/// Merges `overlay` into `base`. Tables are merged key by key, the arrays of
+/// [`APPENDED_ARRAYS`] are appended to, anything else is replaced.
+fn merge(base: &mut toml::Table, overlay: toml::Table, prefix: &str) {
+    for (key, value) in overlay {
+        let path = match prefix {
+            "" => key.clone(),
+            prefix => format!("{prefix}.{key}"),
+        };
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => {
+                merge(base, overlay, &path)
+            }
+            (Some(toml::Value::Array(base)), toml::Value::Array(overlay))
+                if APPENDED_ARRAYS.contains(&path.as_str()) =>
+            {
+                base.extend(overlay)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Moves the `[sysctl.netns]` keys of a user's file, and the deprecated ones directly in
+/// `[sysctl]`, to `[sysctl.users.<username>]`. Keys the file sets there already win.
+fn fold_user_sysctls(table: &mut toml::Table, username: &str) {
+    let Some(toml::Value::Table(sysctl)) = table.get_mut("sysctl") else {
+        return;
+    };
+    let mut netns = match sysctl.remove("netns") {
+        Some(toml::Value::Table(netns)) => netns,
+        // Left for deserializing to report.
+        Some(netns) => {
+            sysctl.insert("netns".to_owned(), netns);
+            return;
+        }
+        None => toml::Table::new(),
+    };
+    let legacy = sysctl
+        .keys()
+        .filter(|key| key.starts_with("net."))
+        .cloned()
+        .collect::<Vec<_>>();
+    for key in legacy {
+        if let Some(value) = sysctl.remove(&key) {
+            netns.entry(key).or_insert(value);
+        }
+    }
+    if netns.is_empty() {
+        return;
+    }
+    let users = sysctl
+        .entry("users")
+        .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+    let Some(users) = users.as_table_mut() else {
+        return;
+    };
+    let user = users
+        .entry(username)
+        .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+    if let Some(user) = user.as_table_mut() {
+        for (key, value) in netns {
+            user.entry(key).or_insert(value);
+        }
+    }
+}
+
+fn config_error(path: &Path, message: impl ToString) -> Error {
+    Error::Config {
+        path: Some(path.to_owned()),
+        message: message.to_string(),
+    }
+}
+
+fn read_config_file(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path).map_err(|err| config_error(path, err))
+}
+
 impl Config {
-    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
-        let content = std::fs::read_to_string(path)?;
-        Ok(toml::from_str(&content)?)
+    /// Loads the config file, with the files of its drop-in directory merged on top.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_files(path.as_ref(), &Self::files(path.as_ref(), None)?, None)
+    }
+
+    /// Like [`Config::load`], with the overrides of the user merged on top, if there are any.
+    ///
+    /// The user's `[sysctl.netns]` becomes `[sysctl.users.<username>]`, so it beats the group
+    /// overrides like the rest of the file beats the shared config.
+    pub fn load_for_user(path: impl AsRef<Path>, username: &str) -> Result<Self> {
+        let files = Self::files(path.as_ref(), Some(username))?;
+        Self::from_files(path.as_ref(), &files, Some(username))
+    }
+
+    fn user_file(path: &Path, username: &str) -> PathBuf {
+        path.with_extension("")
+            .join("users")
+            .join(format!("{username}.toml"))
+    }
+
+    /// The files making up the config of a user, in the order they are merged.
+    ///
+    /// For `/etc/pam_isolate.toml`, these are the file itself, `/etc/pam_isolate.d/*.toml` in
+    /// lexical order and `/etc/pam_isolate/users/<username>.toml`.
+    pub fn files(path: &Path, username: Option<&str>) -> Result<Vec<PathBuf>> {
+        let stem = path.with_extension("");
+        let mut files = vec![path.to_owned()];
+
+        let mut dropins = stem.clone().into_os_string();
+        dropins.push(".d");
+        let dropins = PathBuf::from(dropins);
+        if dropins.is_dir() {
+            let entries = std::fs::read_dir(&dropins).map_err(|err| config_error(&dropins, err))?;
+            let mut dropins = entries
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+                .collect::<Vec<_>>();
+            dropins.sort();
+            files.extend(dropins);
+        }
+
+        if let Some(username) = username {
+            if username.contains('/') || username.starts_with('.') {
+                return Err(Error::config(format!("Invalid user name {username:?}")));
+            }
+            let user_file = Self::user_file(path, username);
+            if user_file.exists() {
+                files.push(user_file);
+            }
+        }
+        Ok(files)
+    }
+
+    /// The users with overrides in the `users` directory next to the config file.
+    pub fn overridden_users(path: &Path) -> Result<Vec<String>> {
+        let users = path.with_extension("").join("users");
+        if !users.is_dir() {
+            return Ok(Vec::new());
+        }
+        let entries = std::fs::read_dir(&users).map_err(|err| config_error(&users, err))?;
+        let mut names = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+            .filter_map(|path| Some(path.file_stem()?.to_str()?.to_owned()))
+            .collect::<Vec<_>>();
+        names.sort();
+        Ok(names)
+    }
+
+    fn from_files(path: &Path, files: &[PathBuf], username: Option<&str>) -> Result<Self> {
+        if let [file] = files {
+            // Parsing the text keeps the location in error messages.
+            return toml::from_str(&read_config_file(file)?).map_err(|err| config_error(file, err));
+        }
+        let mut table = toml::Table::new();
+        for file in files {
+            let content = read_config_file(file)?;
+            let mut overlay = toml::from_str(&content).map_err(|err| config_error(file, err))?;
+            if let Some(username) = username
+                && *file == Self::user_file(path, username)
+            {
+                fold_user_sysctls(&mut overlay, username);
+            }
+            merge(&mut table, overlay, "");
+        }
+        table.try_into().map_err(|err: toml::de::Error| {
+            config_error(path, format!("{} (merged from {files:?})", err.message()))
+        })
     }
 
     pub fn default_path() -> PathBuf {
         ["/", "etc", "pam_isolate.toml"].iter().collect()
     }
+
+    /// Finds the name of the first profile which lists the given PAM service.
+    pub fn profile_for_service(&self, service: &str) -> Option<&str> {
+        let mut names = self.profiles.keys().collect::<Vec<_>>();
+        names.sort();
+        names
+            .into_iter()
+            .find(|name| self.profiles[*name].services.iter().any(|s| s == service))
+            .map(|name| name.as_str())
+    }
+
+    fn port_forwards(&self, username: &str) -> Vec<PortForward> {
+        self.net
+            .port_forwards
+            .iter()
+            .filter(|forward| forward.user == username)
+            .cloned()
+            .collect()
+    }
+
+    fn host_services(&self) -> Vec<HostService> {
+        let mut services = self.net.host_services.iter().collect::<Vec<_>>();
+        services.sort_by_key(|(name, _)| *name);
+        services
+            .into_iter()
+            .map(|(_, service)| service.clone())
+            .collect()
+    }
+
+    /// Resolves the isolation settings of a session for the given profile, or the global settings for `None`.
+    pub fn isolation(&self, context: &SessionContext, profile: Option<&str>) -> Result<Isolation> {
+        let username = context.username.as_str();
+        let Some(name) = profile else {
+            return Ok(Isolation {
+                profile: None,
+                netns: true,
+                mount: self.mount.clone(),
+                sysctl: self.sysctl.for_user(context),
+                port_forwards: self.port_forwards(username),
+                host_services: self.host_services(),
+                abstract_sockets: self.net.abstract_sockets.clone(),
+                accounting: self.accounting.clone(),
+                audit: self.audit.clone(),
+                on_failure: None,
+            });
+        };
+        let Some(profile) = self.profiles.get(name) else {
+            return Err(Error::config(format!("Unknown profile {name:?}")));
+        };
+        let mount = if profile.mntns {
+            profile.mount.as_ref().or(self.mount.as_ref()).cloned()
+        } else {
+            None
+        };
+        let (sysctl, port_forwards, host_services, abstract_sockets, accounting) = if profile.netns
+        {
+            (
+                profile
+                    .sysctl
+                    .as_ref()
+                    .unwrap_or(&self.sysctl)
+                    .for_user(context),
+                self.port_forwards(username),
+                self.host_services(),
+                self.net.abstract_sockets.clone(),
+                self.accounting.clone(),
+            )
+        } else {
+            Default::default()
+        };
+        Ok(Isolation {
+            profile: Some(name.to_owned()),
+            netns: profile.netns,
+            mount,
+            sysctl,
+            port_forwards,
+            host_services,
+            abstract_sockets,
+            accounting,
+            audit: self.audit.clone(),
+            on_failure: profile.on_failure.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sysctl_value(value: &str) -> std::result::Result<SysctlValue, toml::de::Error> {
+        toml::from_str::<HashMap<String, SysctlValue>>(&format!("value = {value}"))
+            .map(|mut table| table.remove("value").unwrap())
+    }
+
+    #[test]
+    fn typed_sysctl_values() {
+        assert_eq!(
+            sysctl_value(r#"{ type = "u8", value = 255 }"#).unwrap(),
+            SysctlValue::Integer(255)
+        );
+        assert!(sysctl_value(r#"{ type = "u8", value = 256 }"#).is_err());
+        assert!(sysctl_value(r#"{ type = "s8", value = -129 }"#).is_err());
+        assert!(sysctl_value(r#"{ type = "u9", value = 1 }"#).is_err());
+        assert!(sysctl_value(r#"{ type = "u8" }"#).is_err());
+    }
+
+    #[test]
+    fn nested_sysctl_arrays() {
+        assert!(sysctl_value("[0, [1, 2]]").is_err());
+    }
+
+    #[test]
+    fn sysctl_arrays_are_joined_by_tabs() {
+        let value = sysctl_value(r#"[0, 2147483647, "x"]"#).unwrap();
+        assert_eq!(value.to_string(), "0\t2147483647\tx");
+        assert_eq!(sysctl_value("[]").unwrap().to_string(), "");
+    }
+
+    #[test]
+    fn merge_appends_only_some_arrays() {
+        let mut base: toml::Table = toml::from_str(
+            r#"
+            [users]
+            ignore = ["root"]
+            [net]
+            abstract_sockets = ["/tmp/a"]
+            [[net.port_forwards]]
+            port = 1
+            [[rules]]
+            action = "skip"
+            "#,
+        )
+        .unwrap();
+        let overlay: toml::Table = toml::from_str(
+            r#"
+            [users]
+            ignore = ["nobody"]
+            [net]
+            abstract_sockets = ["/tmp/b"]
+            [[net.port_forwards]]
+            port = 2
+            [[rules]]
+            action = "isolate"
+            "#,
+        )
+        .unwrap();
+        merge(&mut base, overlay, "");
+
+        let expected: toml::Table = toml::from_str(
+            r#"
+            [users]
+            ignore = ["root", "nobody"]
+            [net]
+            abstract_sockets = ["/tmp/b"]
+            [[net.port_forwards]]
+            port = 1
+            [[net.port_forwards]]
+            port = 2
+            [[rules]]
+            action = "skip"
+            [[rules]]
+            action = "isolate"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(base, expected);
+    }
 }
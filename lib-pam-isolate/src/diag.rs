@@ -0,0 +1,230 @@
+use std::{
+    collections::HashMap,
+    fs::read_dir,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use netlink_sys::{Socket, SocketAddr as NetlinkAddr, protocols::NETLINK_SOCK_DIAG};
+use nix::{
+    fcntl::{OFlag, open},
+    sched::{CloneFlags, setns},
+    sys::stat::Mode,
+};
+use serde::Serialize;
+
+use crate::{Error, IoContext, Protocol, Result};
+
+const SOCK_DIAG_BY_FAMILY: u16 = 20;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_DUMP: u16 = 0x300;
+const NLMSG_HDRLEN: usize = 16;
+const INET_DIAG_MSG_LEN: usize = 72;
+const TCP_CLOSE: u32 = 7;
+const TCP_LISTEN: u32 = 10;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SocketOwner {
+    pub pid: i32,
+    pub command: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ListeningSocket {
+    pub protocol: Protocol,
+    pub address: SocketAddr,
+    pub uid: u32,
+    pub inode: u32,
+    pub owners: Vec<SocketOwner>,
+}
+
+/// Lists the network namespaces created for users, as user name and path.
+pub fn user_namespaces() -> Result<Vec<(String, PathBuf)>> {
+    let dir: PathBuf = ["/", "run", "netns"].iter().collect();
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut namespaces = read_dir(&dir)
+        .io("read", &dir)?
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_str()?.strip_suffix("_ns")?.to_owned();
+            Some((name, entry.path()))
+        })
+        .collect::<Vec<_>>();
+    namespaces.sort();
+    Ok(namespaces)
+}
+
+fn diag_request(family: u8, protocol: Protocol, states: u32) -> Vec<u8> {
+    let ip_protocol = match protocol {
+        Protocol::Tcp => libc::IPPROTO_TCP,
+        Protocol::Udp => libc::IPPROTO_UDP,
+    } as u8;
+    // struct nlmsghdr followed by struct inet_diag_req_v2, with an empty struct inet_diag_sockid.
+    let len = NLMSG_HDRLEN + 56;
+    let mut request = Vec::with_capacity(len);
+    request.extend_from_slice(&(len as u32).to_ne_bytes());
+    request.extend_from_slice(&SOCK_DIAG_BY_FAMILY.to_ne_bytes());
+    request.extend_from_slice(&(NLM_F_REQUEST | NLM_F_DUMP).to_ne_bytes());
+    request.extend_from_slice(&1u32.to_ne_bytes());
+    request.extend_from_slice(&0u32.to_ne_bytes());
+    request.extend_from_slice(&[family, ip_protocol, 0, 0]);
+    request.extend_from_slice(&states.to_ne_bytes());
+    request.resize(len, 0);
+    request
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/// Parses a struct inet_diag_msg.
+fn parse_diag_msg(protocol: Protocol, msg: &[u8]) -> Option<ListeningSocket> {
+    if msg.len() < INET_DIAG_MSG_LEN {
+        return None;
+    }
+    let port = u16::from_be_bytes([msg[4], msg[5]]);
+    let ip = match msg[0] as i32 {
+        libc::AF_INET => IpAddr::V4(Ipv4Addr::new(msg[8], msg[9], msg[10], msg[11])),
+        libc::AF_INET6 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&msg[8..24]).unwrap())),
+        _ => return None,
+    };
+    Some(ListeningSocket {
+        protocol,
+        address: SocketAddr::new(ip, port),
+        uid: u32_at(msg, 64),
+        inode: u32_at(msg, 68),
+        owners: Vec::new(),
+    })
+}
+
+fn query_sockets(
+    socket: &Socket,
+    family: u8,
+    protocol: Protocol,
+    states: u32,
+) -> Result<Vec<ListeningSocket>> {
+    socket
+        .send(&diag_request(family, protocol, states), 0)
+        .syscall("send the sock_diag request")?;
+
+    let mut sockets = Vec::new();
+    let mut buf = Vec::with_capacity(65536);
+    loop {
+        buf.clear();
+        let len = socket
+            .recv(&mut buf, 0)
+            .syscall("receive the sock_diag response")?;
+        let mut offset = 0;
+        while offset + NLMSG_HDRLEN <= len {
+            let msg_len = u32_at(&buf, offset) as usize;
+            let msg_type = u16::from_ne_bytes([buf[offset + 4], buf[offset + 5]]);
+            if msg_len < NLMSG_HDRLEN || offset + msg_len > len {
+                return Err(std::io::Error::from(std::io::ErrorKind::InvalidData))
+                    .syscall("parse the sock_diag response");
+            }
+            match msg_type {
+                NLMSG_DONE => return Ok(sockets),
+                NLMSG_ERROR => {
+                    let errno = -(u32_at(&buf, offset + NLMSG_HDRLEN) as i32);
+                    return Err(std::io::Error::from_raw_os_error(errno)).syscall("sock_diag");
+                }
+                SOCK_DIAG_BY_FAMILY => sockets.extend(parse_diag_msg(
+                    protocol,
+                    &buf[offset + NLMSG_HDRLEN..offset + msg_len],
+                )),
+                _ => {}
+            }
+            // Messages are aligned to 4 bytes.
+            offset += (msg_len + 3) & !3;
+        }
+    }
+}
+
+/// Maps socket inodes to the processes holding them.
+fn socket_owners() -> HashMap<u32, Vec<SocketOwner>> {
+    let mut owners: HashMap<u32, Vec<SocketOwner>> = HashMap::new();
+    let Ok(proc_dir) = read_dir("/proc") else {
+        return owners;
+    };
+    for entry in proc_dir.flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|pid| pid.parse().ok()) else {
+            continue;
+        };
+        let Ok(fds) = read_dir(entry.path().join("fd")) else {
+            continue;
+        };
+        let command = std::fs::read_to_string(entry.path().join("comm"))
+            .map(|comm| comm.trim_end().to_owned())
+            .unwrap_or_default();
+        for fd in fds.flatten() {
+            let Some(inode) = std::fs::read_link(fd.path()).ok().and_then(|link| {
+                link.to_str()?
+                    .strip_prefix("socket:[")?
+                    .strip_suffix(']')?
+                    .parse()
+                    .ok()
+            }) else {
+                continue;
+            };
+            let entry = owners.entry(inode).or_default();
+            if !entry.iter().any(|owner| owner.pid == pid) {
+                entry.push(SocketOwner {
+                    pid,
+                    command: command.clone(),
+                });
+            }
+        }
+    }
+    owners
+}
+
+/// Lists the listening TCP and unconnected UDP sockets inside of a network namespace.
+pub fn listening_sockets(netns_path: &Path) -> Result<Vec<ListeningSocket>> {
+    let netns_fd = open(netns_path, OFlag::O_RDONLY, Mode::empty()).io("open", netns_path)?;
+    // Entering a network namespace only affects the calling thread, so this one is thrown away.
+    let mut sockets = std::thread::scope(|scope| {
+        scope
+            .spawn(|| -> Result<Vec<ListeningSocket>> {
+                setns(&netns_fd, CloneFlags::CLONE_NEWNET).io("setns", netns_path)?;
+                let socket = Socket::new(NETLINK_SOCK_DIAG)
+                    .and_then(|mut socket| {
+                        socket.bind_auto()?;
+                        socket.connect(&NetlinkAddr::new(0, 0))?;
+                        Ok(socket)
+                    })
+                    .syscall("connect to sock_diag")?;
+
+                let mut sockets = Vec::new();
+                for family in [libc::AF_INET, libc::AF_INET6] {
+                    sockets.extend(query_sockets(
+                        &socket,
+                        family as u8,
+                        Protocol::Tcp,
+                        1 << TCP_LISTEN,
+                    )?);
+                    sockets.extend(query_sockets(
+                        &socket,
+                        family as u8,
+                        Protocol::Udp,
+                        1 << TCP_CLOSE,
+                    )?);
+                }
+                Ok(sockets)
+            })
+            .join()
+            .map_err(|_| Error::Syscall {
+                operation: "query sock_diag".to_owned(),
+                source: std::io::Error::other("the thread panicked"),
+            })?
+    })?;
+
+    let mut owners = socket_owners();
+    for socket in &mut sockets {
+        socket.owners = owners.remove(&socket.inode).unwrap_or_default();
+    }
+    Ok(sockets)
+}
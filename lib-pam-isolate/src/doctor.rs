@@ -0,0 +1,314 @@
+use std::{collections::BTreeSet, ffi::CStr, fmt, fs::read_to_string, path::PathBuf};
+
+use nix::sched::{CloneFlags, unshare};
+use rtnetlink::new_connection;
+use serde::Serialize;
+use tokio::runtime::Runtime;
+
+use crate::{Config, Error, IoContext, MAX_UID, MIN_UID, Result, get_link};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Ok,
+    /// Some sessions might fail, or it's fixed automatically.
+    Warning,
+    /// Sessions will fail to be set up.
+    Error,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Ok => "ok",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+}
+
+/// The result of a check of the host.
+#[derive(Debug, Clone, Serialize)]
+pub struct Finding {
+    pub check: &'static str,
+    pub severity: Severity,
+    pub message: String,
+    /// How to fix it.
+    pub hint: Option<String>,
+}
+
+impl Finding {
+    fn ok(check: &'static str, message: impl Into<String>) -> Self {
+        Finding {
+            check,
+            severity: Severity::Ok,
+            message: message.into(),
+            hint: None,
+        }
+    }
+
+    fn problem(
+        check: &'static str,
+        severity: Severity,
+        message: impl Into<String>,
+        hint: impl Into<String>,
+    ) -> Self {
+        Finding {
+            check,
+            severity,
+            message: message.into(),
+            hint: Some(hint.into()),
+        }
+    }
+
+    fn failed(check: &'static str, err: impl fmt::Display) -> Self {
+        Finding {
+            check,
+            severity: Severity::Error,
+            message: format!("Couldn't check: {err}"),
+            hint: None,
+        }
+    }
+}
+
+/// A line of `/proc/self/mountinfo`.
+struct MountInfo {
+    mount_point: PathBuf,
+    shared: bool,
+}
+
+/// Undoes the octal escapes of spaces and other special characters.
+fn unescape(field: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut rest = field.as_bytes();
+    while let Some(&byte) = rest.first() {
+        if byte == b'\\'
+            && let Some(Ok(octal)) = rest.get(1..4).map(std::str::from_utf8)
+            && let Ok(escaped) = u8::from_str_radix(octal, 8)
+        {
+            bytes.push(escaped);
+            rest = &rest[4..];
+        } else {
+            bytes.push(byte);
+            rest = &rest[1..];
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn mounts() -> Result<Vec<MountInfo>> {
+    Ok(read_to_string("/proc/self/mountinfo")
+        .io("read", "/proc/self/mountinfo")?
+        .lines()
+        .filter_map(|line| {
+            let (fields, _) = line.split_once(" - ")?;
+            let fields = fields.split(' ').collect::<Vec<_>>();
+            Some(MountInfo {
+                mount_point: PathBuf::from(unescape(fields.get(4)?)),
+                shared: fields
+                    .get(6..)
+                    .unwrap_or_default()
+                    .iter()
+                    .any(|field| field.starts_with("shared:")),
+            })
+        })
+        .collect())
+}
+
+fn check_root_propagation(mounts: &[MountInfo]) -> Finding {
+    const CHECK: &str = "root-propagation";
+    // Later mounts on the same point hide the earlier ones.
+    match mounts
+        .iter()
+        .rev()
+        .find(|mount| mount.mount_point.as_os_str() == "/")
+    {
+        Some(root) if root.shared => Finding::problem(
+            CHECK,
+            Severity::Error,
+            "/ is a shared mount, so the mounts of the users would show up on the host",
+            "Run `mount --make-private /` after every boot, e.g. from a systemd unit",
+        ),
+        Some(_) => Finding::ok(CHECK, "/ is private"),
+        None => Finding::failed(CHECK, "/ is missing from the mount table"),
+    }
+}
+
+fn check_tmp_mounts(config: &Config, mounts: &[MountInfo]) -> Vec<Finding> {
+    const CHECK: &str = "tmp-mount";
+    let paths = config
+        .mount
+        .iter()
+        .chain(
+            config
+                .profiles
+                .values()
+                .filter(|profile| profile.mntns)
+                .filter_map(|profile| profile.mount.as_ref()),
+        )
+        .map(|mount| PathBuf::from(&mount.tmp))
+        .collect::<BTreeSet<_>>();
+    paths
+        .into_iter()
+        .map(|path| {
+            if mounts.iter().any(|mount| mount.mount_point == path) {
+                Finding::ok(CHECK, format!("{path:?} is a mount point"))
+            } else {
+                Finding::problem(
+                    CHECK,
+                    Severity::Error,
+                    format!("{path:?} is not a mount point, so it can't be replaced by a tmpfs"),
+                    format!(
+                        "Mount a filesystem on {path:?}, e.g. with `systemctl enable tmp.mount`"
+                    ),
+                )
+            }
+        })
+        .collect()
+}
+
+fn check_netns_dir() -> Finding {
+    const CHECK: &str = "netns-dir";
+    let dir: PathBuf = ["/", "run", "netns"].iter().collect();
+    if dir.is_dir() {
+        Finding::ok(CHECK, format!("{dir:?} exists"))
+    } else {
+        Finding::problem(
+            CHECK,
+            Severity::Warning,
+            format!("{dir:?} doesn't exist"),
+            format!(
+                "It is created on the first login, which needs a writable {:?}",
+                dir.parent().unwrap_or(&dir)
+            ),
+        )
+    }
+}
+
+fn check_forwarding() -> Vec<Finding> {
+    const CHECK: &str = "ip-forward";
+    [
+        (
+            "net.ipv4.ip_forward",
+            "/proc/sys/net/ipv4/ip_forward",
+            Severity::Error,
+        ),
+        (
+            "net.ipv6.conf.all.forwarding",
+            "/proc/sys/net/ipv6/conf/all/forwarding",
+            Severity::Warning,
+        ),
+    ]
+    .into_iter()
+    .map(|(key, path, severity)| match read_to_string(path) {
+        Ok(value) if value.trim() == "1" => Finding::ok(CHECK, format!("{key} is enabled")),
+        Ok(_) => Finding::problem(
+            CHECK,
+            severity,
+            format!("{key} is disabled, so the namespaces can't reach anything but the host"),
+            format!("Set {key} = 1 in /etc/sysctl.d/ and run `sysctl --system`"),
+        ),
+        Err(err) => Finding::failed(CHECK, format!("{path}: {err}")),
+    })
+    .collect()
+}
+
+/// Users with a login shell and a UID outside of the range the addresses are derived from.
+fn unsupported_users(config: &Config) -> Vec<String> {
+    let mut users = Vec::new();
+    // SAFETY: Nothing else reads the user database meanwhile.
+    unsafe {
+        libc::setpwent();
+        loop {
+            let entry = libc::getpwent();
+            if entry.is_null() {
+                break;
+            }
+            let entry = &*entry;
+            let name = CStr::from_ptr(entry.pw_name).to_string_lossy().into_owned();
+            let shell = CStr::from_ptr(entry.pw_shell).to_string_lossy();
+            if !(MIN_UID..=MAX_UID).contains(&entry.pw_uid)
+                && !shell.ends_with("nologin")
+                && !shell.ends_with("false")
+                && !config.users.ignore.contains(&name)
+            {
+                users.push(name);
+            }
+        }
+        libc::endpwent();
+    }
+    users
+}
+
+fn check_uids(config: &Config) -> Finding {
+    const CHECK: &str = "uid-range";
+    let users = unsupported_users(config);
+    if users.is_empty() {
+        return Finding::ok(
+            CHECK,
+            format!("All users with a login shell have a UID from {MIN_UID} to {MAX_UID}"),
+        );
+    }
+    Finding::problem(
+        CHECK,
+        Severity::Warning,
+        format!(
+            "The UIDs of {} are outside of the supported range from {MIN_UID} to {MAX_UID}",
+            users.join(", ")
+        ),
+        "Add them to users.ignore, or skip their sessions with a rule",
+    )
+}
+
+fn check_loopback(rt: &Runtime, loopback: &str) -> Finding {
+    const CHECK: &str = "loopback";
+    // Looked up in a new network namespace, like the one a session gets.
+    let exists = std::thread::scope(|scope| {
+        scope
+            .spawn(|| -> Result<bool> {
+                unshare(CloneFlags::CLONE_NEWNET).syscall("unshare the network namespace")?;
+                rt.block_on(async {
+                    let (connection, handle, _) = new_connection().syscall("connect to netlink")?;
+                    tokio::spawn(connection);
+                    Ok(get_link(&handle, loopback).await?.is_some())
+                })
+            })
+            .join()
+            .unwrap_or_else(|_| {
+                Err(Error::Syscall {
+                    operation: "check the loopback interface".to_owned(),
+                    source: std::io::Error::other("the thread panicked"),
+                })
+            })
+    });
+    match exists {
+        Ok(true) => Finding::ok(
+            CHECK,
+            format!("{loopback} exists in new network namespaces"),
+        ),
+        Ok(false) => Finding::problem(
+            CHECK,
+            Severity::Error,
+            format!("There is no interface {loopback} in new network namespaces"),
+            "Set net.loopback to the name of the loopback interface, usually \"lo\"",
+        ),
+        Err(err) => Finding::failed(CHECK, err),
+    }
+}
+
+/// Checks the prerequisites of the host for the given configuration. This needs root.
+pub fn run_doctor(rt: &Runtime, config: &Config) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    match mounts() {
+        Ok(mounts) => {
+            findings.push(check_root_propagation(&mounts));
+            findings.extend(check_tmp_mounts(config, &mounts));
+        }
+        Err(err) => findings.push(Finding::failed("mounts", err)),
+    }
+    findings.push(check_netns_dir());
+    findings.extend(check_forwarding());
+    findings.push(check_uids(config));
+    findings.push(check_loopback(rt, &config.net.loopback));
+    findings
+}
@@ -0,0 +1,141 @@
+use std::path::{Path, PathBuf};
+
+use nix::errno::Errno;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Errors while setting up the isolation of a session.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The configuration couldn't be loaded or is inconsistent.
+    #[error("config error{}: {message}", path.as_ref().map(|path| format!(" in {path:?}")).unwrap_or_default())]
+    Config {
+        path: Option<PathBuf>,
+        message: String,
+    },
+    /// The user or their groups couldn't be looked up.
+    #[error("failed to look up user {user}: {errno}")]
+    UserLookup { user: String, errno: Errno },
+    /// The addresses of the network namespace are derived from the UID, which limits its range.
+    #[error("UID {0} is out of the supported range from {min} to {max}", min = crate::MIN_UID, max = crate::MAX_UID)]
+    UnsupportedUid(u32),
+    /// The per-UID lock couldn't be taken or released.
+    #[error("failed to lock {path:?}: {source}")]
+    Lock {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    /// A netlink request, or creating the network namespace, failed.
+    #[error("netlink {operation} on {interface} failed: {source}")]
+    Netlink {
+        operation: &'static str,
+        /// The interface or network namespace.
+        interface: String,
+        #[source]
+        source: rtnetlink::Error,
+    },
+    /// Mounting or unmounting failed.
+    #[error("{operation} of {path:?} failed: {errno}")]
+    Mount {
+        operation: &'static str,
+        path: PathBuf,
+        errno: Errno,
+    },
+    /// A sysctl couldn't be set.
+    #[error("failed to set {key}: {source}")]
+    Sysctl {
+        key: String,
+        #[source]
+        source: sysctl::SysctlError,
+    },
+    /// Setting up the nftables rules failed.
+    #[error("nft failed: {0}")]
+    Nft(String),
+    /// A file operation.
+    #[error("{operation} {path:?} failed: {source}")]
+    Io {
+        operation: &'static str,
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    /// Any other system call, e.g. `fork`, `setns` or dropping privileges.
+    #[error("{operation} failed: {source}")]
+    Syscall {
+        operation: String,
+        #[source]
+        source: std::io::Error,
+    },
+    /// An existing network namespace is still broken after setting it up again.
+    #[error("failed to repair the network namespace: {}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "))]
+    Unrepaired(Vec<crate::NetnsDefect>),
+    /// The proxy for abstract sockets couldn't be started.
+    #[error("failed to start the abstract socket proxy: {0}")]
+    Proxy(String),
+    #[error("the session was entered already")]
+    AlreadyEntered,
+}
+
+impl Error {
+    pub(crate) fn config(message: impl Into<String>) -> Self {
+        Error::Config {
+            path: None,
+            message: message.into(),
+        }
+    }
+
+    /// The errno behind the error, if any.
+    pub fn errno(&self) -> Option<Errno> {
+        match self {
+            Error::UserLookup { errno, .. } | Error::Mount { errno, .. } => Some(*errno),
+            Error::Lock { source, .. }
+            | Error::Io { source, .. }
+            | Error::Syscall { source, .. } => source.raw_os_error().map(Errno::from_raw),
+            Error::Netlink {
+                source: rtnetlink::Error::NetlinkError(message),
+                ..
+            } => message.to_io().raw_os_error().map(Errno::from_raw),
+            _ => None,
+        }
+    }
+}
+
+/// Adds the operation, and the path for file operations, to errors of system calls.
+pub(crate) trait IoContext<T> {
+    fn io(self, operation: &'static str, path: impl AsRef<Path>) -> Result<T>;
+    fn syscall(self, operation: impl Into<String>) -> Result<T>;
+}
+
+impl<T, E: Into<std::io::Error>> IoContext<T> for std::result::Result<T, E> {
+    fn io(self, operation: &'static str, path: impl AsRef<Path>) -> Result<T> {
+        self.map_err(|err| Error::Io {
+            operation,
+            path: path.as_ref().to_owned(),
+            source: err.into(),
+        })
+    }
+
+    fn syscall(self, operation: impl Into<String>) -> Result<T> {
+        self.map_err(|err| Error::Syscall {
+            operation: operation.into(),
+            source: err.into(),
+        })
+    }
+}
+
+/// Adds the operation and interface to netlink errors.
+pub(crate) trait NetlinkContext<T> {
+    fn netlink(self, operation: &'static str, interface: &str) -> Result<T>;
+}
+
+impl<T> NetlinkContext<T> for std::result::Result<T, rtnetlink::Error> {
+    fn netlink(self, operation: &'static str, interface: &str) -> Result<T> {
+        self.map_err(|source| Error::Netlink {
+            operation,
+            interface: interface.to_owned(),
+            source,
+        })
+    }
+}
@@ -0,0 +1,135 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use futures::TryStreamExt;
+use rtnetlink::{
+    Handle, RouteMessageBuilder,
+    packet_route::{
+        address::AddressAttribute,
+        link::LinkFlags,
+        route::{RouteAddress, RouteAttribute},
+    },
+};
+
+use crate::{AddressPair, NetlinkContext, Result, get_link};
+
+/// Something missing from the network of a user's namespace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetnsDefect {
+    MissingLink(String),
+    LinkDown(String),
+    MissingAddress { interface: String, address: IpAddr },
+    MissingDefaultRoute { interface: String, gateway: IpAddr },
+}
+
+impl fmt::Display for NetnsDefect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetnsDefect::MissingLink(name) => write!(f, "{name} is missing"),
+            NetnsDefect::LinkDown(name) => write!(f, "{name} is down"),
+            NetnsDefect::MissingAddress { interface, address } => {
+                write!(f, "{interface} lacks {address}")
+            }
+            NetnsDefect::MissingDefaultRoute { interface, gateway } => {
+                write!(f, "no default route via {gateway} on {interface}")
+            }
+        }
+    }
+}
+
+async fn addresses(handle: &Handle, index: u32, name: &str) -> Result<Vec<IpAddr>> {
+    let messages: Vec<_> = handle
+        .address()
+        .get()
+        .set_link_index_filter(index)
+        .execute()
+        .try_collect()
+        .await
+        .netlink("get addresses", name)?;
+    Ok(messages
+        .iter()
+        .flat_map(|message| &message.attributes)
+        .filter_map(|attribute| match attribute {
+            AddressAttribute::Address(address) => Some(*address),
+            _ => None,
+        })
+        .collect())
+}
+
+async fn has_default_route(
+    handle: &Handle,
+    index: u32,
+    name: &str,
+    gateway: IpAddr,
+) -> Result<bool> {
+    let message = match gateway {
+        IpAddr::V4(_) => RouteMessageBuilder::<Ipv4Addr>::default().build(),
+        IpAddr::V6(_) => RouteMessageBuilder::<Ipv6Addr>::default().build(),
+    };
+    let routes: Vec<_> = handle
+        .route()
+        .get(message)
+        .execute()
+        .try_collect()
+        .await
+        .netlink("get routes", name)?;
+    Ok(routes.iter().any(|route| {
+        route.header.destination_prefix_length == 0
+            && route.attributes.contains(&RouteAttribute::Oif(index))
+            && route
+                .attributes
+                .contains(&RouteAttribute::Gateway(RouteAddress::from(gateway)))
+    }))
+}
+
+/// Checks that a link exists, is up and has the given addresses.
+pub(crate) async fn check_link(
+    handle: &Handle,
+    name: &str,
+    expected: Option<&AddressPair>,
+) -> Result<Vec<NetnsDefect>> {
+    let Some(link) = get_link(handle, name).await? else {
+        return Ok(vec![NetnsDefect::MissingLink(name.to_owned())]);
+    };
+    let mut defects = Vec::new();
+    if !link.header.flags.contains(LinkFlags::Up) {
+        defects.push(NetnsDefect::LinkDown(name.to_owned()));
+    }
+    if let Some(expected) = expected {
+        let present = addresses(handle, link.header.index, name).await?;
+        for address in [IpAddr::V4(expected.v4), IpAddr::V6(expected.v6)] {
+            if !present.contains(&address) {
+                defects.push(NetnsDefect::MissingAddress {
+                    interface: name.to_owned(),
+                    address,
+                });
+            }
+        }
+    }
+    Ok(defects)
+}
+
+/// Checks the inside of a network namespace, `handle` has to be connected from within it.
+pub(crate) async fn check_inside(
+    handle: &Handle,
+    loopback: &str,
+    in_name: &str,
+    inside: &AddressPair,
+    outside: &AddressPair,
+) -> Result<Vec<NetnsDefect>> {
+    let mut defects = check_link(handle, loopback, None).await?;
+    defects.extend(check_link(handle, in_name, Some(inside)).await?);
+    if let Some(link) = get_link(handle, in_name).await? {
+        for gateway in [IpAddr::V4(outside.v4), IpAddr::V6(outside.v6)] {
+            if !has_default_route(handle, link.header.index, in_name, gateway).await? {
+                defects.push(NetnsDefect::MissingDefaultRoute {
+                    interface: in_name.to_owned(),
+                    gateway,
+                });
+            }
+        }
+    }
+    Ok(defects)
+}
@@ -1,52 +1,109 @@
-#![allow(clippy::too_many_arguments)]
 use std::{
     collections::HashMap,
-    ffi::OsStr,
-    fs::{OpenOptions, read_dir},
+    fs::{File, OpenOptions, read_dir},
     io::{BufRead, BufReader},
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
-    os::{fd::AsRawFd, unix::prelude::OsStrExt},
+    os::fd::{AsFd, AsRawFd, OwnedFd},
     path::{Path, PathBuf},
 };
 
 use fs4::fs_std::FileExt;
 use futures::{StreamExt, stream::TryStreamExt};
 use nix::{
+    errno::Errno,
     fcntl::{OFlag, open},
     mount::{MsFlags, mount, umount},
     sched::{CloneFlags, setns, unshare},
     sys::stat::Mode,
-    unistd::{Gid, Pid, Uid, close, getpid},
+    unistd::{Gid, Pid, Uid, User, close, gethostname, getpid},
 };
 use rtnetlink::{
     LinkMessageBuilder, LinkUnspec, LinkVeth, NetworkNamespace, RouteMessageBuilder,
-    new_connection, packet_route::link::LinkAttribute,
+    new_connection,
+    packet_route::link::{LinkAttribute, LinkMessage},
 };
-use sysctl::{Ctl, CtlValue, Sysctl};
+use sysctl::{Ctl, Sysctl};
 use tokio::runtime::Runtime;
 
+mod accounting;
+mod audit;
 mod config;
+mod diag;
+mod doctor;
+mod error;
+mod health;
+mod logging;
+mod metrics;
+mod nat;
+mod rollback;
+mod rules;
+mod session;
+mod sockets;
+mod validate;
+pub use accounting::*;
+pub use audit::*;
 pub use config::*;
+pub use diag::*;
+pub use doctor::*;
+pub use error::{Error, Result};
+pub(crate) use error::{IoContext, NetlinkContext};
+pub use health::NetnsDefect;
+pub use logging::*;
+pub use metrics::*;
+use rollback::Rollback;
+pub use rules::*;
+pub use session::*;
+
+#[derive(Debug, Clone, Copy)]
+pub struct AddressPair {
+    pub v4: Ipv4Addr,
+    pub v4_prefix_len: u8,
+    pub v6: Ipv6Addr,
+    pub v6_prefix_len: u8,
+}
 
-struct AddressPair {
-    v4: Ipv4Addr,
-    v4_prefix_len: u8,
-    v6: Ipv6Addr,
-    v6_prefix_len: u8,
+/// The network namespace of a session.
+#[derive(Debug, Clone)]
+pub struct NetnsInfo {
+    pub name: String,
+    pub path: PathBuf,
+    /// The addresses of `veth_{uid}_in`, inside of the namespace.
+    pub inside: AddressPair,
+    /// The addresses of `veth_{uid}_out`, on the host.
+    pub outside: AddressPair,
+    pub setup: NamespaceSetup,
 }
 
-fn generate_veth_addresses(uid: Uid) -> anyhow::Result<(AddressPair, AddressPair)> {
-    if uid.as_raw() < 1000 {
-        anyhow::bail!("UID must be at least 1000, got {}", uid.as_raw());
-    }
+/// How a session got one of its namespaces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NamespaceSetup {
+    Created,
+    /// The namespace of another session of the user.
+    Joined,
+    /// Joined, after fixing what went missing since it was created.
+    Repaired,
+}
+
+/// Describes the namespaces `create_namespaces` created or joined.
+#[derive(Debug, Clone, Default)]
+pub struct SessionInfo {
+    pub netns: Option<NetnsInfo>,
+    /// The mount configuration, if the session got a mount namespace.
+    pub mount: Option<Mount>,
+    /// Whether the mount namespace was created or joined.
+    pub mount_setup: Option<NamespaceSetup>,
+}
+
+/// The range of UIDs the addresses of a network namespace can be derived from.
+pub const MIN_UID: u32 = 1000;
+pub const MAX_UID: u32 = 1 << 14;
+
+fn generate_veth_addresses(uid: Uid) -> Result<(AddressPair, AddressPair)> {
     // we got 14 bits to work with in 100.b01yyyyyy.yyyyyyyy.1/2
-    if uid.as_raw() > 2u32.pow(14) {
-        anyhow::bail!(
-            "UID must be less than or equal to 2^14, got {}",
-            uid.as_raw()
-        );
+    if !(MIN_UID..=MAX_UID).contains(&uid.as_raw()) {
+        return Err(Error::UnsupportedUid(uid.as_raw()));
     }
-    let uid = (uid.as_raw() - 1000) as u16;
+    let uid = (uid.as_raw() - MIN_UID) as u16;
     let [uid_upper, uid_lower] = uid.to_be_bytes();
 
     Ok((
@@ -65,179 +122,512 @@ fn generate_veth_addresses(uid: Uid) -> anyhow::Result<(AddressPair, AddressPair
     ))
 }
 
-async fn get_link_index(handle: &rtnetlink::Handle, name: &str) -> anyhow::Result<Option<u32>> {
+impl SessionInfo {
+    /// Fills the placeholders of a login message template.
+    ///
+    /// Placeholders for namespaces the session doesn't have are replaced with an empty string.
+    pub fn render_message(&self, template: &str, username: &str) -> String {
+        let hostname = gethostname()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let netns = self.netns.as_ref();
+        let mount = self.mount.as_ref();
+        let placeholders = [
+            ("{user}", username.to_owned()),
+            ("{hostname}", hostname),
+            (
+                "{ipv4}",
+                netns.map(|n| n.inside.v4.to_string()).unwrap_or_default(),
+            ),
+            (
+                "{ipv6}",
+                netns.map(|n| n.inside.v6.to_string()).unwrap_or_default(),
+            ),
+            (
+                "{outside_ipv4}",
+                netns.map(|n| n.outside.v4.to_string()).unwrap_or_default(),
+            ),
+            (
+                "{outside_ipv6}",
+                netns.map(|n| n.outside.v6.to_string()).unwrap_or_default(),
+            ),
+            ("{netns}", netns.map(|n| n.name.clone()).unwrap_or_default()),
+            ("{tmp}", mount.map(|m| m.tmp.clone()).unwrap_or_default()),
+            (
+                "{tmp_size}",
+                mount.map(|m| m.size.clone()).unwrap_or_default(),
+            ),
+        ];
+        // A single pass, so placeholders within the values are kept as they are.
+        let mut message = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            message.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let placeholder = rest.find('}').and_then(|end| {
+                placeholders
+                    .iter()
+                    .find(|(placeholder, _)| *placeholder == &rest[..=end])
+            });
+            match placeholder {
+                Some((placeholder, value)) => {
+                    message.push_str(value);
+                    rest = &rest[placeholder.len()..];
+                }
+                None => {
+                    message.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        message.push_str(rest);
+        message
+    }
+}
+
+/// Holds the locks and other runtime state of pam_isolate.
+pub(crate) fn run_path() -> PathBuf {
+    ["/", "var", "run", "pam_isolate"].iter().collect()
+}
+
+/// The name of a user's network namespace, as shown by `ip netns`.
+pub fn netns_name(username: &str) -> String {
+    format!("{username}_ns")
+}
+
+pub fn netns_path(username: &str) -> PathBuf {
+    ["/", "run", "netns", &netns_name(username)]
+        .iter()
+        .collect()
+}
+
+/// Opens the namespace of the given kind the calling thread is in, e.g. `net`.
+fn current_namespace(kind: &str) -> Result<OwnedFd> {
+    let path: PathBuf = ["/", "proc", "thread-self", "ns", kind].iter().collect();
+    open(&path, OFlag::O_RDONLY, Mode::empty()).io("open", &path)
+}
+
+/// Runs `f` in the given network namespace and switches back to the current one afterwards.
+fn in_netns<T>(netns_fd: impl AsFd, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let current_fd = current_namespace("net")?;
+    setns(netns_fd, CloneFlags::CLONE_NEWNET).syscall("setns to a network namespace")?;
+    let result = f();
+    setns(current_fd, CloneFlags::CLONE_NEWNET)
+        .syscall("setns back to the previous network namespace")?;
+    result
+}
+
+async fn get_link(handle: &rtnetlink::Handle, name: &str) -> Result<Option<LinkMessage>> {
     let mut links = handle.link().get().match_name(name.to_owned()).execute();
 
-    if let Some(link) = links.try_next().await? {
+    let link = match links.try_next().await.netlink("get", name) {
+        // The kernel reports a missing link as an error.
+        Err(err) if err.errno() == Some(Errno::ENODEV) => None,
+        result => result?,
+    };
+    if link.is_some() {
         links.collect::<Vec<_>>().await; // drain stream
-        Ok(Some(link.header.index))
+    }
+    Ok(link)
+}
+
+async fn get_link_index(handle: &rtnetlink::Handle, name: &str) -> Result<Option<u32>> {
+    Ok(get_link(handle, name).await?.map(|link| link.header.index))
+}
+
+/// Adds both addresses of the pair, keeping them if they exist already.
+async fn add_addresses(
+    handle: &rtnetlink::Handle,
+    index: u32,
+    name: &str,
+    addresses: &AddressPair,
+) -> Result<()> {
+    for (address, prefix_len) in [
+        (IpAddr::V4(addresses.v4), addresses.v4_prefix_len),
+        (IpAddr::V6(addresses.v6), addresses.v6_prefix_len),
+    ] {
+        match handle
+            .address()
+            .add(index, address, prefix_len)
+            .execute()
+            .await
+            .netlink("add address", name)
+        {
+            Err(err) if err.errno() == Some(Errno::EEXIST) => {}
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+async fn set_link_up(handle: &rtnetlink::Handle, index: u32, name: &str) -> Result<()> {
+    handle
+        .link()
+        .set(
+            LinkMessageBuilder::<LinkUnspec>::default()
+                .index(index)
+                .up()
+                .build(),
+        )
+        .execute()
+        .await
+        .netlink("set up", name)
+}
+
+/// Creates the veth pair, with its inside end in the given network namespace.
+async fn create_veth(
+    handle: &rtnetlink::Handle,
+    in_name: &str,
+    out_name: &str,
+    netns_fd: &OwnedFd,
+) -> Result<()> {
+    handle
+        .link()
+        .add(
+            LinkVeth::new(in_name, out_name)
+                .append_extra_attribute(LinkAttribute::NetNsFd(netns_fd.as_raw_fd()))
+                .build(),
+        )
+        .execute()
+        .await
+        .netlink("create", out_name)?;
+    log::info!("[pam_isolate] Link created");
+    Ok(())
+}
+
+/// Configures the outside end of the veth pair. `handle` has to be connected from the host.
+async fn setup_outside(
+    handle: &rtnetlink::Handle,
+    out_name: &str,
+    out_addr: &AddressPair,
+    isolation: &Isolation,
+    host_netns_fd: &OwnedFd,
+) -> Result<()> {
+    if let Some(out_index) = get_link_index(handle, out_name).await? {
+        add_addresses(handle, out_index, out_name, out_addr).await?;
+        log::info!("[pam_isolate] Outside addresses set");
+        set_link_up(handle, out_index, out_name).await?;
+        log::info!("[pam_isolate] Outside interface set UP");
+    }
+
+    if nat::needs_route_localnet(&isolation.host_services) {
+        // Host services listening on localhost are only reachable this way.
+        in_netns(host_netns_fd, || {
+            let key = format!("net.ipv4.conf.{out_name}.route_localnet");
+            Ctl::new(&key)
+                .and_then(|ctl| ctl.set_value_string("1"))
+                .map_err(|source| Error::Sysctl { key, source })?;
+            Ok(())
+        })?;
+        log::info!("[pam_isolate] Enabled route_localnet on {out_name}");
+    }
+
+    let sysctls = isolation.sysctl.host_iface_sysctls(out_name);
+    if !sysctls.is_empty() {
+        in_netns(host_netns_fd, || {
+            try_setup_sysctl(&sysctls);
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+/// Replaces the DNAT rules of a user. It has to be called from the host's network namespace.
+fn setup_nat(
+    uid: Uid,
+    out_name: &str,
+    out_addr: &AddressPair,
+    in_addr: &AddressPair,
+    isolation: &Isolation,
+) -> Result<()> {
+    let _phase = Phase::start("nat");
+    nat::setup_nat(uid, out_name, out_addr, in_addr, isolation)?;
+    log::info!(
+        "[pam_isolate] Set up {} port forwards and {} host services for {out_name}",
+        isolation.port_forwards.len(),
+        isolation.host_services.len()
+    );
+    Ok(())
+}
+
+/// Configures loopback and the inside end of the veth pair. `handle` has to be connected from
+/// within the namespace.
+async fn setup_inside(
+    handle: &rtnetlink::Handle,
+    loopback: &str,
+    in_name: &str,
+    in_addr: &AddressPair,
+    out_addr: &AddressPair,
+) -> Result<()> {
+    if let Some(lo_index) = get_link_index(handle, loopback).await? {
+        set_link_up(handle, lo_index, loopback).await?;
+        log::info!("[pam_isolate] Found loopback at index {lo_index}, set UP");
     } else {
-        Ok(None)
+        log::info!("[pam_isolate] Could not find lookpack interface");
+    }
+
+    if let Some(in_index) = get_link_index(handle, in_name).await? {
+        add_addresses(handle, in_index, in_name, in_addr).await?;
+        log::info!("[pam_isolate] Inside addresses set");
+        set_link_up(handle, in_index, in_name).await?;
+        log::info!("[pam_isolate] Inside interface set UP");
+
+        handle
+            .route()
+            .add(
+                RouteMessageBuilder::<Ipv4Addr>::default()
+                    .destination_prefix(Ipv4Addr::UNSPECIFIED, 0)
+                    .gateway(out_addr.v4)
+                    .output_interface(in_index)
+                    .build(),
+            )
+            .replace()
+            .execute()
+            .await
+            .netlink("add route", in_name)?;
+        handle
+            .route()
+            .add(
+                RouteMessageBuilder::<Ipv6Addr>::default()
+                    .destination_prefix(Ipv6Addr::UNSPECIFIED, 0)
+                    .gateway(out_addr.v6)
+                    .output_interface(in_index)
+                    .build(),
+            )
+            .replace()
+            .execute()
+            .await
+            .netlink("add route", in_name)?;
+        log::info!("[pam_isolate] Default routes added");
     }
+    Ok(())
 }
 
-async fn create_interface(username: &str, uid: Uid, loopback: &str) -> anyhow::Result<()> {
+async fn create_interface(
+    session: &SessionContext,
+    gid: Gid,
+    loopback: &str,
+    isolation: &Isolation,
+    rollback: &mut Rollback,
+) -> Result<NetnsInfo> {
     log::debug!("[pam_isolate] Starting network setup");
 
-    let netns = format!("{username}_ns");
-    let netns_path = ["/", "run", "netns", &netns].iter().collect::<PathBuf>();
+    let (username, uid) = (session.username.as_str(), session.uid);
+    let audit = isolation.audit.as_ref();
+    let netns = netns_name(username);
+    let netns_path = netns_path(username);
+    let (out_addr, in_addr) = generate_veth_addresses(uid)?;
+    let info = NetnsInfo {
+        name: netns.clone(),
+        path: netns_path.clone(),
+        inside: in_addr,
+        outside: out_addr,
+        setup: NamespaceSetup::Created,
+    };
+    update_log_context(|context| context.netns = Some(netns.clone()));
+
+    let (connection, handle, _) = new_connection().syscall("connect to netlink")?;
+    tokio::spawn(connection);
+
+    let out_name = format!("veth_{uid}_out");
+    let in_name = format!("veth_{uid}_in");
 
     if netns_path.exists() {
-        let netns_fd = open(Path::new(&netns_path), OFlag::O_RDONLY, Mode::empty())?;
-        setns(netns_fd, CloneFlags::CLONE_NEWNET)?;
+        let _phase = Phase::start("join");
+        let netns_fd = open(&netns_path, OFlag::O_RDONLY, Mode::empty()).io("open", &netns_path)?;
+
+        // A restart of the host's network or `ip link del` might have left it without a link.
+        let mut defects = health::check_link(&handle, &out_name, Some(&out_addr)).await?;
+        if defects.contains(&NetnsDefect::MissingLink(out_name.clone())) {
+            create_veth(&handle, &in_name, &out_name, &netns_fd).await?;
+            rollback.veth(out_name.clone());
+        }
+        let mut remaining = Vec::new();
+        if !defects.is_empty() {
+            let host_netns_fd = current_namespace("net")?;
+            setup_outside(&handle, &out_name, &out_addr, isolation, &host_netns_fd).await?;
+            remaining = health::check_link(&handle, &out_name, Some(&out_addr)).await?;
+        }
+        // The config might have changed, or the rules might have been flushed since. Not rolled
+        // back, as the other sessions in the namespace rely on them.
+        setup_nat(uid, &out_name, &out_addr, &in_addr, isolation)?;
 
-        log::info!("[pam_isolate] Joined existing namespace.");
-        Ok(())
-    } else {
-        let (connection, handle, _) = new_connection()?;
+        let previous = current_namespace("net")?;
+        setns(netns_fd, CloneFlags::CLONE_NEWNET).io("setns", &netns_path)?;
+        rollback.entered(previous, CloneFlags::CLONE_NEWNET);
+
+        let (connection, handle, _) = new_connection().syscall("connect to netlink")?;
         tokio::spawn(connection);
+        let inside = health::check_inside(&handle, loopback, &in_name, &in_addr, &out_addr).await?;
+        if !inside.is_empty() {
+            setup_inside(&handle, loopback, &in_name, &in_addr, &out_addr).await?;
+            remaining.extend(
+                health::check_inside(&handle, loopback, &in_name, &in_addr, &out_addr).await?,
+            );
+        }
+        defects.extend(inside);
 
-        let out_name = format!("veth_{uid}_out");
-        let in_name = format!("veth_{uid}_in");
+        // Only what is gone after setting it up again counts as repaired.
+        if !remaining.is_empty() {
+            return Err(Error::Unrepaired(remaining));
+        }
 
+        if defects.is_empty() {
+            log::info!("[pam_isolate] Joined existing namespace.");
+            rollback.audit(AuditAction::Joined, NamespaceKind::Net);
+            Ok(NetnsInfo {
+                setup: NamespaceSetup::Joined,
+                ..info
+            })
+        } else {
+            let defects = defects.iter().map(ToString::to_string).collect::<Vec<_>>();
+            log::warn!(
+                "[pam_isolate] Repaired existing namespace: {}",
+                defects.join(", ")
+            );
+            rollback.audit(AuditAction::Repaired, NamespaceKind::Net);
+            Ok(NetnsInfo {
+                setup: NamespaceSetup::Repaired,
+                ..info
+            })
+        }
+    } else {
+        let phase = Phase::start("cleanup");
+        // The proxy of a previous namespace would keep it alive.
+        sockets::stop_abstract_proxy(uid)?;
         log::info!("[pam_isolate] Checking if {out_name} already exists from a previous user");
         match get_link_index(&handle, &out_name).await {
             Ok(Some(out_index)) => {
                 log::info!("[pam_isolate] Interface {out_name} already exists. Cleaning up...");
-                handle.link().del(out_index).execute().await?;
+                if let Some(accounting) = &isolation.accounting {
+                    // Its counters are gone with the interface, so they have to be saved first.
+                    let recorded =
+                        accounting::read_sample(&handle, &out_name)
+                            .await
+                            .and_then(|sample| match sample {
+                                Some(sample) => accounting::record_sample(accounting, uid, &sample),
+                                None => Ok(()),
+                            });
+                    if let Err(err) = recorded {
+                        log::warn!(
+                            "[pam_isolate] Failed to record the traffic of {out_name}: {err:?}"
+                        );
+                    }
+                }
+                handle
+                    .link()
+                    .del(out_index)
+                    .execute()
+                    .await
+                    .netlink("delete", &out_name)?;
                 log::info!("[pam_isolate] Deleted existing interface {out_name}");
+                nat::remove_nat(uid)?;
+                log::info!("[pam_isolate] Removed the DNAT rules of {out_name}");
+                audit::audit(
+                    audit,
+                    session,
+                    AuditAction::StaleCleanup,
+                    NamespaceKind::Net,
+                );
             }
             Ok(None) => {
                 log::info!("[pam_isolate] Interface {out_name} does not exist. Proceeding...");
             }
             Err(e) => {
-                if let Some(io_err) = e.downcast_ref::<std::io::Error>() {
-                    if io_err.raw_os_error() != Some(19) {
-                        log::warn!(
-                            "[pam_isolate] Failed to check existence of {out_name}: {e}. Proceeding..."
-                        );
-                    }
-                }
+                log::warn!(
+                    "[pam_isolate] Failed to check existence of {out_name}: {e}. Proceeding..."
+                );
             }
         }
 
-        let netns_path = NetworkNamespace::child_process_create_ns(netns)?;
-        NetworkNamespace::unshare_processing(netns_path.clone())?;
+        drop(phase);
+
+        // This replaces the rules left behind by a previous user, too.
+        setup_nat(uid, &out_name, &out_addr, &in_addr, isolation)?;
+        rollback.nat(uid);
+
+        // Keep a handle to the host's namespace, for settings that have to be applied there.
+        let host_netns_fd = open("/proc/self/ns/net", OFlag::O_RDONLY, Mode::empty())
+            .io("open", "/proc/self/ns/net")?;
+
+        let phase = Phase::start("netns");
+        let netns_path =
+            NetworkNamespace::child_process_create_ns(netns.clone()).netlink("create", &netns)?;
+        rollback.netns(PathBuf::from(&netns_path));
+        let previous = host_netns_fd.try_clone().io("dup", "/proc/self/ns/net")?;
+        NetworkNamespace::unshare_processing(netns_path.clone()).netlink("enter", &netns)?;
+        rollback.entered(previous, CloneFlags::CLONE_NEWNET);
         log::info!("[pam_isolate] Created net namespace {netns_path:?}");
 
-        let netns_fd = open(Path::new(&netns_path), OFlag::O_RDONLY, Mode::empty())?;
+        let netns_fd =
+            open(Path::new(&netns_path), OFlag::O_RDONLY, Mode::empty()).io("open", &netns_path)?;
         log::info!("[pam_isolate] Netns file created");
+        drop(phase);
 
-        let links = handle.link().add(
-            LinkVeth::new(&in_name, &out_name)
-                .append_extra_attribute(LinkAttribute::NetNsFd(netns_fd.as_raw_fd()))
-                .build(),
-        );
-        links.execute().await?;
-        close(netns_fd)?;
-        log::info!("[pam_isolate] Link created");
-
-        let (out_addr, in_addr) = generate_veth_addresses(uid)?;
-        if let Some(out_index) = get_link_index(&handle, &out_name).await? {
-            handle
-                .address()
-                .add(out_index, IpAddr::V4(out_addr.v4), out_addr.v4_prefix_len)
-                .execute()
-                .await?;
-            handle
-                .address()
-                .add(out_index, IpAddr::V6(out_addr.v6), out_addr.v6_prefix_len)
-                .execute()
-                .await?;
-            log::info!("[pam_isolate] Outside addresses set");
-
-            handle
-                .link()
-                .set(
-                    LinkMessageBuilder::<LinkUnspec>::default()
-                        .index(out_index)
-                        .up()
-                        .build(),
-                )
-                .execute()
-                .await?;
-            log::info!("[pam_isolate] Outside interface set UP");
-        }
+        let phase = Phase::start("veth");
+        create_veth(&handle, &in_name, &out_name, &netns_fd).await?;
+        rollback.veth(out_name.clone());
+        close(netns_fd).io("close", &netns_path)?;
+        drop(phase);
 
+        let phase = Phase::start("outside_addresses");
+        setup_outside(&handle, &out_name, &out_addr, isolation, &host_netns_fd).await?;
+        drop(phase);
+
+        let phase = Phase::start("inside_addresses");
         // We need to set up a new connection here in order to move to the new namespace for this operation.
-        let (connection, handle, _) = new_connection()?;
+        let (connection, handle, _) = new_connection().syscall("connect to netlink")?;
         tokio::spawn(connection);
+        setup_inside(&handle, loopback, &in_name, &in_addr, &out_addr).await?;
+        drop(phase);
 
-        if let Some(lo_index) = get_link_index(&handle, loopback).await? {
-            handle
-                .link()
-                .set(
-                    LinkMessageBuilder::<LinkUnspec>::default()
-                        .index(lo_index)
-                        .up()
-                        .build(),
-                )
-                .execute()
-                .await?;
-            log::info!("[pam_isolate] Found loopback at index {lo_index}, set UP");
-        } else {
-            log::info!("[pam_isolate] Could not find lookpack interface");
+        if !isolation.sysctl.netns.is_empty() {
+            // Only once, so changes made later on are kept for the next sessions.
+            let _phase = Phase::start("sysctl");
+            try_setup_sysctl(&isolation.sysctl.netns);
         }
 
-        if let Some(in_index) = get_link_index(&handle, &in_name).await? {
-            handle
-                .address()
-                .add(in_index, IpAddr::V4(in_addr.v4), in_addr.v4_prefix_len)
-                .execute()
-                .await?;
-            handle
-                .address()
-                .add(in_index, IpAddr::V6(in_addr.v6), in_addr.v6_prefix_len)
-                .execute()
-                .await?;
-            log::info!("[pam_isolate] Inside addresses set");
-
-            handle
-                .link()
-                .set(
-                    LinkMessageBuilder::<LinkUnspec>::default()
-                        .index(in_index)
-                        .up()
-                        .build(),
-                )
-                .execute()
-                .await?;
-            log::info!("[pam_isolate] Inside interface set UP");
-
-            handle
-                .route()
-                .add(
-                    RouteMessageBuilder::<Ipv4Addr>::default()
-                        .destination_prefix(Ipv4Addr::UNSPECIFIED, 0)
-                        .gateway(out_addr.v4)
-                        .output_interface(in_index)
-                        .build(),
-                )
-                .replace()
-                .execute()
-                .await?;
-            handle
-                .route()
-                .add(
-                    RouteMessageBuilder::<Ipv6Addr>::default()
-                        .destination_prefix(Ipv6Addr::UNSPECIFIED, 0)
-                        .gateway(out_addr.v6)
-                        .output_interface(in_index)
-                        .build(),
-                )
-                .replace()
-                .execute()
-                .await?;
-            log::info!("[pam_isolate] Default routes added");
+        if !isolation.abstract_sockets.is_empty() {
+            let _phase = Phase::start("proxy");
+            sockets::spawn_abstract_proxy(&isolation.abstract_sockets, &host_netns_fd, uid, gid)?;
+            rollback.proxy(uid);
         }
+        drop(host_netns_fd);
+
+        rollback.audit(AuditAction::Created, NamespaceKind::Net);
+        Ok(info)
+    }
+}
+
+/// Whether a process exited while reading its files in `/proc`.
+fn vanished(err: &std::io::Error) -> bool {
+    err.kind() == std::io::ErrorKind::NotFound || err.raw_os_error() == Some(libc::ESRCH)
+}
 
-        Ok(())
+/// Whether a process is in the mount namespace with the given device and inode, `None` if it
+/// exited meanwhile.
+fn in_mount_namespace(process: &Path, (dev, ino): (u64, u64)) -> Result<Option<bool>> {
+    let path = process.join("ns").join("mnt");
+    match nix::sys::stat::stat(&path) {
+        Ok(ns) => Ok(Some((ns.st_dev, ns.st_ino) == (dev, ino))),
+        Err(errno) if vanished(&errno.into()) => Ok(None),
+        Err(errno) => Err(errno).io("stat", &path),
     }
 }
 
-fn get_first_process_by_uid_or_env(uid: Uid, user_env: &str) -> anyhow::Result<Option<Pid>> {
+/// Finds a process whose mount namespace a session of the user can join.
+///
+/// Processes still in the mount namespace of the caller, i.e. the host's, are skipped. They were
+/// never isolated, e.g. because their profile has no mount namespace or a rule skipped them.
+fn get_first_process_by_uid_or_env(
+    uid: Uid,
+    user_env: &str,
+    ignored_exe: Option<&Path>,
+) -> Result<Option<Pid>> {
+    let own = nix::sys::stat::stat("/proc/self/ns/mnt").io("stat", "/proc/self/ns/mnt")?;
+    let own = (own.st_dev, own.st_ino);
     // Read the '/proc' directory
-    let proc_dir = read_dir("/proc")?;
+    let proc_dir = read_dir("/proc").io("read", "/proc")?;
     let user_env = user_env.as_bytes();
     let uid_string = uid.to_string();
     let uid_bytes = uid_string.as_bytes();
@@ -245,45 +635,62 @@ fn get_first_process_by_uid_or_env(uid: Uid, user_env: &str) -> anyhow::Result<O
     // Find the PID of a process belonging to the specified user
     for entry in proc_dir.flatten() {
         let entry_path = entry.path();
-        if let Some(pid_str) = entry_path.file_name().and_then(|s| s.to_str()) {
-            if let Ok(pid) = pid_str.parse() {
-                // Check if the process belongs to the specified user
-                if let Ok(status) = nix::sys::stat::stat(entry_path.join("status").as_path()) {
-                    if Uid::from_raw(status.st_uid) == uid {
-                        let exe = nix::fcntl::readlink(&entry_path.join("exe"))?;
-                        if exe != OsStr::from_bytes(b"/usr/lib/systemd/systemd") {
-                            // systemd creates some processes for a logged in user to manage the PAM session.
-                            // Since those don't operate under the namespace, we have to ignore them.
-                            return Ok(Some(Pid::from_raw(pid)));
-                        }
-                    } else if status.st_uid == 0 {
-                        // Alternatively, this process could also be in the process of becoming the specified user.
-                        // This is here to avoid a race condition, because in a PAM module we can't unlock our
-                        // lockfile after the call to `setuid()`, it has to happen before that.
-                        let mut environ_path = entry_path.clone();
-                        environ_path.push("environ");
-                        let mut environ = BufReader::new(std::fs::File::open(environ_path)?);
-                        let mut buffer = Vec::new();
-                        while environ.read_until(0, &mut buffer)? > 0 {
-                            let mut iter = buffer.splitn(2, |c| *c == b'=');
-                            if iter.next().unwrap() == user_env
-                                && iter
-                                    .next()
-                                    .filter(|&content| {
-                                        !content.is_empty()
-                                            && &content[..content.len() - 1] == uid_bytes
-                                        // skip \0 at the end of the content
-                                    })
-                                    .is_some()
-                            {
-                                log::info!(
-                                    "[pam_isolate] Found process to be used for the user {uid} with pid {pid}"
-                                );
-                                return Ok(Some(Pid::from_raw(pid)));
-                            }
-                            buffer.clear();
+        if let Some(pid_str) = entry_path.file_name().and_then(|s| s.to_str())
+            && let Ok(pid) = pid_str.parse()
+            // Check if the process belongs to the specified user
+            && let Ok(status) = nix::sys::stat::stat(entry_path.join("status").as_path())
+        {
+            if Uid::from_raw(status.st_uid) == uid {
+                let exe_path = entry_path.join("exe");
+                let exe = match nix::fcntl::readlink(&exe_path) {
+                    Ok(exe) => exe,
+                    Err(errno) if vanished(&errno.into()) => continue,
+                    Err(errno) => return Err(errno).io("readlink", &exe_path),
+                };
+                // systemd creates some processes for a logged in user to manage the PAM session.
+                // Unless the user manager is isolated as well, those don't operate under the
+                // namespace, so we have to ignore them.
+                if ignored_exe.is_none_or(|ignored_exe| exe != ignored_exe.as_os_str())
+                    && in_mount_namespace(&entry_path, own)? == Some(false)
+                {
+                    return Ok(Some(Pid::from_raw(pid)));
+                }
+            } else if status.st_uid == 0 {
+                // Alternatively, this process could also be in the process of becoming the specified user.
+                // This is here to avoid a race condition, because in a PAM module we can't unlock our
+                // lockfile after the call to `setuid()`, it has to happen before that.
+                let mut environ_path = entry_path.clone();
+                environ_path.push("environ");
+                let mut environ = match File::open(&environ_path) {
+                    Ok(file) => BufReader::new(file),
+                    Err(err) if vanished(&err) => continue,
+                    Err(err) => return Err(err).io("open", &environ_path),
+                };
+                let mut buffer = Vec::new();
+                while environ
+                    .read_until(0, &mut buffer)
+                    .io("read", &environ_path)?
+                    > 0
+                {
+                    let mut iter = buffer.splitn(2, |c| *c == b'=');
+                    if iter.next().unwrap() == user_env
+                        && iter
+                            .next()
+                            .filter(|&content| {
+                                !content.is_empty() && &content[..content.len() - 1] == uid_bytes
+                                // skip \0 at the end of the content
+                            })
+                            .is_some()
+                    {
+                        if in_mount_namespace(&entry_path, own)? != Some(false) {
+                            break;
                         }
+                        log::info!(
+                            "[pam_isolate] Found process to be used for the user {uid} with pid {pid}"
+                        );
+                        return Ok(Some(Pid::from_raw(pid)));
                     }
+                    buffer.clear();
                 }
             }
         }
@@ -292,40 +699,80 @@ fn get_first_process_by_uid_or_env(uid: Uid, user_env: &str) -> anyhow::Result<O
     Ok(None)
 }
 
-fn create_namespaces_exclusive(
+/// Sets up the namespaces, and undoes everything done so far if a step fails.
+fn create_namespaces_exclusive(rt: &Runtime, session: &Session) -> Result<SessionInfo> {
+    let mut rollback = Rollback::default();
+    match setup_namespaces(rt, session, &mut rollback) {
+        Ok(info) => {
+            rollback.commit(session.isolation.audit.as_ref(), &session.context);
+            Ok(info)
+        }
+        Err(err) => {
+            log::error!("[pam_isolate] Setting up the namespaces failed, rolling back: {err}");
+            rt.block_on(rollback.undo());
+            Err(err)
+        }
+    }
+}
+
+fn setup_namespaces(
     rt: &Runtime,
-    username: &str,
-    uid: Uid,
-    gid: Gid,
-    mount_config: &Option<config::Mount>,
-    user_env: &str,
-    loopback: &str,
-) -> anyhow::Result<()> {
-    rt.block_on(create_interface(username, uid, loopback))?;
+    session: &Session,
+    rollback: &mut Rollback,
+) -> Result<SessionInfo> {
+    let isolation = &session.isolation;
+    let (gid, loopback) = (session.gid, session.loopback.as_str());
+    let session_context = &session.context;
+    let mut info = SessionInfo::default();
+    if isolation.netns {
+        info.netns = Some(rt.block_on(create_interface(
+            session_context,
+            gid,
+            loopback,
+            isolation,
+            rollback,
+        ))?);
+    }
 
-    let mount_config = match mount_config {
+    let mount_config = match &isolation.mount {
         Some(val) => val,
-        None => return Ok(()),
+        None => return Ok(info),
     };
+    info.mount = Some(mount_config.clone());
+    let _phase = Phase::start("mount");
 
-    let first_pid = get_first_process_by_uid_or_env(uid, user_env)?;
+    let uid = session_context.uid;
+    let first_pid =
+        get_first_process_by_uid_or_env(uid, &session.user_env, session.ignored_exe.as_deref())?;
 
     if let Some(first_pid) = first_pid {
         log::info!("[pam_isolate] Attaching to namespace of pid {first_pid}");
-        let mntns_fd = open(
-            &["/", "proc", &first_pid.to_string(), "ns", "mnt"]
-                .iter()
-                .collect::<PathBuf>(),
-            OFlag::O_RDONLY,
-            Mode::empty(),
-        )?;
-        setns(mntns_fd, CloneFlags::CLONE_NEWNS)?;
+        let mntns_path = ["/", "proc", &first_pid.to_string(), "ns", "mnt"]
+            .iter()
+            .collect::<PathBuf>();
+        let mntns_fd = open(&mntns_path, OFlag::O_RDONLY, Mode::empty()).io("open", &mntns_path)?;
+        let previous = current_namespace("mnt")?;
+        setns(mntns_fd, CloneFlags::CLONE_NEWNS).io("setns", &mntns_path)?;
+        rollback.entered(previous, CloneFlags::CLONE_NEWNS);
         log::info!("[pam_isolate] Attachment successful.");
+        info.mount_setup = Some(NamespaceSetup::Joined);
+        rollback.audit(AuditAction::Joined, NamespaceKind::Mnt);
     } else {
-        unshare(CloneFlags::CLONE_NEWNS)?;
+        let previous = current_namespace("mnt")?;
+        unshare(CloneFlags::CLONE_NEWNS).syscall("unshare the mount namespace")?;
+        // The mounts below are gone with the namespace once it is left.
+        rollback.entered(previous, CloneFlags::CLONE_NEWNS);
         log::debug!("[pam_isolate] unshare(CLONE_NEWNS) successful.");
+        let socket_dirs = sockets::open_socket_dirs(&mount_config.sockets)?;
 
-        umount(mount_config.tmp.as_str())?;
+        let mount_error = |operation| {
+            move |errno| Error::Mount {
+                operation,
+                path: PathBuf::from(&mount_config.tmp),
+                errno,
+            }
+        };
+        umount(mount_config.tmp.as_str()).map_err(mount_error("umount"))?;
         mount(
             Some("tmpfs"),
             mount_config.tmp.as_str(),
@@ -338,28 +785,41 @@ fn create_namespaces_exclusive(
                 )
                 .as_str(),
             ),
-        )?;
+        )
+        .map_err(mount_error("tmpfs mount"))?;
+        sockets::bind_sockets(&socket_dirs)?;
+        info.mount_setup = Some(NamespaceSetup::Created);
+        rollback.audit(AuditAction::Created, NamespaceKind::Mnt);
     }
-    Ok(())
+    Ok(info)
 }
 
-pub fn create_namespaces(
-    rt: &Runtime,
-    username: &str,
-    uid: Uid,
-    gid: Gid,
-    mount_config: &Option<config::Mount>,
-    user_env: &str,
-    loopback: &str,
-    set_env: impl Fn(&str, &str),
-) -> anyhow::Result<()> {
-    if user_env.contains('=') {
-        return Err(anyhow::anyhow!(
-            "Don't use `=` within the user environment variable name!"
-        ));
+fn export_env(env: &Env, info: &SessionInfo, set_env: impl Fn(&str, &str)) {
+    let mut vars = Vec::new();
+    if let Some(netns) = &info.netns {
+        vars.extend([
+            (&env.inside_ipv4, netns.inside.v4.to_string()),
+            (&env.inside_ipv6, netns.inside.v6.to_string()),
+            (&env.outside_ipv4, netns.outside.v4.to_string()),
+            (&env.outside_ipv6, netns.outside.v6.to_string()),
+            (&env.netns, netns.name.clone()),
+            (&env.netns_path, netns.path.to_string_lossy().into_owned()),
+        ]);
     }
-    let run_path: PathBuf = ["/", "var", "run", "pam_isolate"].iter().collect();
-    std::fs::create_dir_all(&run_path)?;
+    for (name, value) in vars {
+        if let Some(name) = name {
+            set_env(name, &value);
+            log::debug!("[pam_isolate] set {name}={value}");
+        }
+    }
+}
+
+/// Takes the per-UID lock, which serializes changes to the namespaces of a user.
+///
+/// It is released when the file is closed.
+fn lock_user(uid: Uid) -> Result<(File, PathBuf)> {
+    let run_path = run_path();
+    std::fs::create_dir_all(&run_path).io("create", &run_path)?;
 
     let mut lock_path = run_path;
     lock_path.push(format!("lock_{uid}"));
@@ -374,8 +834,34 @@ pub fn create_namespaces(
         .write(true)
         .truncate(false)
         .create(true)
-        .open(&lock_path)?;
-    lock_file.lock_exclusive()?;
+        .open(&lock_path)
+        .io("open", &lock_path)?;
+    lock_file.lock_exclusive().map_err(|source| Error::Lock {
+        path: lock_path.clone(),
+        source,
+    })?;
+    Ok((lock_file, lock_path))
+}
+
+/// Creates or joins the namespaces of a session.
+///
+/// Events for the audit log are written while holding the per-UID lock, so their order is the
+/// order in which the namespaces were set up.
+pub(crate) fn create_namespaces(
+    rt: &Runtime,
+    session: &Session,
+    set_env: impl Fn(&str, &str),
+) -> Result<SessionInfo> {
+    let (user_env, env) = (session.user_env.as_str(), &session.env);
+    if user_env.contains('=') || env.names().any(|name| name.contains('=')) {
+        return Err(Error::config(
+            "Don't use `=` within the user environment variable name!",
+        ));
+    }
+    let uid = session.context.uid;
+    let phase = Phase::start("lock");
+    let (lock_file, lock_path) = lock_user(uid)?;
+    drop(phase);
     set_env(user_env, &uid.to_string());
     log::debug!("[pam_isolate] set {user_env}={uid}");
     for (var, content) in std::env::vars() {
@@ -383,83 +869,62 @@ pub fn create_namespaces(
     }
 
     // We have to make sure to unlock the file afterwards, even in the case of an error!
-    let result =
-        create_namespaces_exclusive(rt, username, uid, gid, mount_config, user_env, loopback);
+    let result = create_namespaces_exclusive(rt, session);
     let result2 = fs4::fs_std::FileExt::unlock(&lock_file);
 
-    if result.is_err() {
-        drop(lock_file);
-        result
-    } else {
-        result2.map_err(|err| err.into())
+    match result {
+        Ok(info) => {
+            result2.map_err(|source| Error::Lock {
+                path: lock_path,
+                source,
+            })?;
+            export_env(env, &info, set_env);
+            Ok(info)
+        }
+        Err(err) => {
+            drop(lock_file);
+            Err(err)
+        }
     }
 }
 
-pub fn try_setup_sysctl(table: &HashMap<String, toml::Value>) {
-    for (key, value) in table {
-        let value = match value {
-            toml::Value::String(value) => CtlValue::String(value.clone()),
-            toml::Value::Integer(value) => CtlValue::S64(*value),
-            toml::Value::Table(value) => {
-                if let Some(ty) = value.get("type").and_then(|ty| ty.as_str()) {
-                    match ty {
-                        "uint" if matches!(value.get("value"), Some(toml::Value::Integer(_))) => {
-                            CtlValue::Uint(value.get("value").unwrap().as_integer().unwrap() as _)
-                        }
-                        "ulong" if matches!(value.get("value"), Some(toml::Value::Integer(_))) => {
-                            CtlValue::Ulong(value.get("value").unwrap().as_integer().unwrap() as _)
-                        }
-                        "u8" if matches!(value.get("value"), Some(toml::Value::Integer(_))) => {
-                            CtlValue::U8(value.get("value").unwrap().as_integer().unwrap() as _)
-                        }
-                        "u16" if matches!(value.get("value"), Some(toml::Value::Integer(_))) => {
-                            CtlValue::U16(value.get("value").unwrap().as_integer().unwrap() as _)
-                        }
-                        "u32" if matches!(value.get("value"), Some(toml::Value::Integer(_))) => {
-                            CtlValue::U32(value.get("value").unwrap().as_integer().unwrap() as _)
-                        }
-                        "u64" if matches!(value.get("value"), Some(toml::Value::Integer(_))) => {
-                            CtlValue::U64(value.get("value").unwrap().as_integer().unwrap() as _)
-                        }
-                        "int" if matches!(value.get("value"), Some(toml::Value::Integer(_))) => {
-                            CtlValue::Int(value.get("value").unwrap().as_integer().unwrap() as _)
-                        }
-                        "long" if matches!(value.get("value"), Some(toml::Value::Integer(_))) => {
-                            CtlValue::Long(value.get("value").unwrap().as_integer().unwrap() as _)
-                        }
-                        "s8" if matches!(value.get("value"), Some(toml::Value::Integer(_))) => {
-                            CtlValue::S8(value.get("value").unwrap().as_integer().unwrap() as _)
-                        }
-                        "s16" if matches!(value.get("value"), Some(toml::Value::Integer(_))) => {
-                            CtlValue::S16(value.get("value").unwrap().as_integer().unwrap() as _)
-                        }
-                        "s32" if matches!(value.get("value"), Some(toml::Value::Integer(_))) => {
-                            CtlValue::S32(value.get("value").unwrap().as_integer().unwrap() as _)
-                        }
-                        "s64" if matches!(value.get("value"), Some(toml::Value::Integer(_))) => {
-                            CtlValue::S64(value.get("value").unwrap().as_integer().unwrap() as _)
-                        }
-                        _ => {
-                            log::error!(
-                                "[pam_isolate] Unknown type {ty:?} for typed sysctl entry \"{key}\""
-                            );
-                            continue;
-                        }
-                    }
-                } else {
-                    log::error!("[pam_isolate] Invalid format for typed sysctl entry \"{key}\"");
-                    continue;
-                }
-            }
-            _ => {
-                log::error!(
-                    "[pam_isolate] Unhandled sysctl value type {value:?} for entry \"{key}\""
-                );
-                continue;
-            }
-        };
-        if let Err(err) = Ctl::new(key).and_then(|ctl| ctl.set_value(value)) {
-            log::error!("[pam_isolate] Failed setting sysctl \"{key}\": {err}");
-        }
+/// Sets the sysctls in the current network namespace, returning the failures.
+fn set_sysctls(table: &HashMap<String, SysctlValue>) -> Vec<Error> {
+    let mut keys = table.keys().collect::<Vec<_>>();
+    keys.sort();
+    keys.into_iter()
+        .filter_map(|key| {
+            Ctl::new(key)
+                .and_then(|ctl| ctl.set_value_string(&table[key].to_string()))
+                .err()
+                .map(|source| Error::Sysctl {
+                    key: key.clone(),
+                    source,
+                })
+        })
+        .collect()
+}
+
+/// Sets the sysctls in the current network namespace, logging failures.
+pub fn try_setup_sysctl(table: &HashMap<String, SysctlValue>) {
+    for err in set_sysctls(table) {
+        log::error!("[pam_isolate] Failed setting sysctl: {err}");
     }
 }
+
+/// Sets the sysctls of a user's existing network namespace again, e.g. after changing the config.
+///
+/// Sessions only set them when the namespace is created, to keep changes made inside of it.
+/// Returns the sysctls that couldn't be set, the others are set nevertheless. Holds the per-UID
+/// lock meanwhile, so a session can't create the namespace anew at the same time.
+pub fn reapply_sysctls(user: &User, isolation: &Isolation) -> Result<Vec<Error>> {
+    let _lock = lock_user(user.uid)?;
+    let path = netns_path(&user.name);
+    let netns_fd = open(&path, OFlag::O_RDONLY, Mode::empty()).io("open", &path)?;
+    let out_name = format!("veth_{}_out", user.uid);
+    let mut failures = set_sysctls(&isolation.sysctl.host_iface_sysctls(&out_name));
+    failures.extend(in_netns(netns_fd, || {
+        Ok(set_sysctls(&isolation.sysctl.netns))
+    })?);
+    Ok(failures)
+}
@@ -0,0 +1,126 @@
+use std::{sync::Mutex, time::Instant};
+
+use log::{
+    Log, Metadata, Record,
+    kv::{self, Key, Source, Value, VisitSource},
+};
+use nix::unistd::Uid;
+
+/// What is known about the current session, attached to every log record as journal fields.
+#[derive(Debug, Clone, Default)]
+pub struct LogContext {
+    pub user: Option<String>,
+    pub uid: Option<Uid>,
+    pub netns: Option<String>,
+    pub service: Option<String>,
+    phase: Option<&'static str>,
+}
+
+static CONTEXT: Mutex<LogContext> = Mutex::new(LogContext {
+    user: None,
+    uid: None,
+    netns: None,
+    service: None,
+    phase: None,
+});
+
+pub fn update_log_context(f: impl FnOnce(&mut LogContext)) {
+    if let Ok(mut context) = CONTEXT.lock() {
+        f(&mut context);
+    }
+}
+
+/// Forgets the previous session, whose fields would end up in the records of the next one in
+/// long-running processes.
+pub fn reset_log_context() {
+    update_log_context(|context| *context = LogContext::default());
+}
+
+impl LogContext {
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        [
+            ("pam_isolate_user", self.user.clone()),
+            ("uid", self.uid.map(|uid| uid.to_string())),
+            ("netns", self.netns.clone()),
+            ("pam_service", self.service.clone()),
+            ("phase", self.phase.map(str::to_owned)),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key, value?)))
+        .collect()
+    }
+}
+
+struct WithContext<'a> {
+    fields: &'a [(&'static str, String)],
+    record: &'a dyn Source,
+}
+
+impl Source for WithContext<'_> {
+    fn visit<'kvs>(&'kvs self, visitor: &mut dyn VisitSource<'kvs>) -> Result<(), kv::Error> {
+        for (key, value) in self.fields {
+            visitor.visit_pair(Key::from_str(key), Value::from(value.as_str()))?;
+        }
+        self.record.visit(visitor)
+    }
+}
+
+/// Adds the fields of the [`LogContext`] to the records passed to another logger.
+pub struct ContextLogger<L>(pub L);
+
+impl<L: Log> Log for ContextLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let fields = match CONTEXT.lock() {
+            Ok(context) => context.fields(),
+            Err(_) => Vec::new(),
+        };
+        let source = WithContext {
+            fields: &fields,
+            record: record.key_values(),
+        };
+        self.0.log(&record.to_builder().key_values(&source).build());
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
+
+/// Times a step of the session setup.
+///
+/// Records logged meanwhile get its name as `PHASE`. When dropped, also on errors, the duration is
+/// logged as `DURATION_USEC`.
+pub(crate) struct Phase {
+    name: &'static str,
+    started: Instant,
+    /// The phase this one is nested in.
+    outer: Option<&'static str>,
+}
+
+impl Phase {
+    pub(crate) fn start(name: &'static str) -> Self {
+        let mut outer = None;
+        update_log_context(|context| outer = context.phase.replace(name));
+        Phase {
+            name,
+            started: Instant::now(),
+            outer,
+        }
+    }
+}
+
+impl Drop for Phase {
+    fn drop(&mut self) {
+        let duration_usec = self.started.elapsed().as_micros() as u64;
+        log::info!(
+            duration_usec;
+            "[pam_isolate] Phase {} took {duration_usec}us",
+            self.name
+        );
+        update_log_context(|context| context.phase = self.outer);
+    }
+}
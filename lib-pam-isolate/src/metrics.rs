@@ -0,0 +1,326 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Write as _,
+    fs::{OpenOptions, read_dir},
+    io::{Read, Seek, Write},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use fs4::fs_std::FileExt;
+use nix::{
+    sys::statvfs::statvfs,
+    unistd::{Uid, User},
+};
+use rtnetlink::new_connection;
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Runtime;
+
+use crate::{
+    Config, Error, IoContext, Result, Sample, accounting::read_sample, netns_path, run_path,
+    user_namespaces,
+};
+
+/// Login setup statistics of a user, kept in the run directory.
+///
+/// They start over after a reboot, like all Prometheus counters may.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LoginStats {
+    pub setups: u64,
+    pub failures: u64,
+    pub setup_seconds_sum: f64,
+    pub last_setup_seconds: f64,
+}
+
+fn stats_path(uid: Uid) -> PathBuf {
+    run_path().join(format!("stats_{uid}.json"))
+}
+
+/// Counts a session setup of a user, taking `duration`.
+pub fn record_login(uid: Uid, duration: Duration, success: bool) -> Result<()> {
+    std::fs::create_dir_all(run_path()).io("create", run_path())?;
+    let path = stats_path(uid);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .truncate(false)
+        .create(true)
+        .open(&path)
+        .io("open", &path)?;
+    // Not the per-UID lock of `create_namespaces`, failures are recorded after it was released.
+    file.lock_exclusive().map_err(|source| Error::Lock {
+        path: path.clone(),
+        source,
+    })?;
+
+    let mut content = String::new();
+    file.read_to_string(&mut content).io("read", &path)?;
+    let mut stats: LoginStats = serde_json::from_str(&content).unwrap_or_default();
+    stats.setups += 1;
+    if !success {
+        stats.failures += 1;
+    }
+    stats.setup_seconds_sum += duration.as_secs_f64();
+    stats.last_setup_seconds = duration.as_secs_f64();
+
+    let content = serde_json::to_vec(&stats).io("serialize the stats for", &path)?;
+    file.set_len(0)
+        .and_then(|_| file.rewind())
+        .and_then(|_| file.write_all(&content))
+        .io("write", &path)
+}
+
+fn login_stats() -> Result<BTreeMap<u32, LoginStats>> {
+    let mut stats = BTreeMap::new();
+    let Ok(entries) = read_dir(run_path()) else {
+        return Ok(stats);
+    };
+    for entry in entries.flatten() {
+        let Some(uid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix("stats_")?.strip_suffix(".json"))
+            .and_then(|uid| uid.parse().ok())
+        else {
+            continue;
+        };
+        let path = entry.path();
+        let file = std::fs::File::open(&path).io("open", &path)?;
+        file.lock_shared()
+            .map_err(|source| Error::Lock { path, source })?;
+        if let Ok(user_stats) = serde_json::from_reader(&file) {
+            stats.insert(uid, user_stats);
+        }
+    }
+    Ok(stats)
+}
+
+/// Counts the distinct audit sessions of the processes in a network namespace.
+fn session_count(netns: &Path) -> Result<usize> {
+    let netns = std::fs::metadata(netns).io("stat", netns)?;
+    let mut sessions = HashSet::new();
+    for entry in read_dir("/proc").io("read", "/proc")?.flatten() {
+        let Ok(ns) = std::fs::metadata(entry.path().join("ns").join("net")) else {
+            continue;
+        };
+        if ns.dev() != netns.dev() || ns.ino() != netns.ino() {
+            continue;
+        }
+        let Ok(session) = std::fs::read_to_string(entry.path().join("sessionid")) else {
+            continue;
+        };
+        // Processes which never went through a login have an unset session.
+        if session != u32::MAX.to_string() {
+            sessions.insert(session);
+        }
+    }
+    Ok(sessions.len())
+}
+
+/// Size and used bytes of the tmpfs of a user, seen through a process in its mount namespace.
+fn tmp_usage(config: &Config, uid: Uid) -> Result<Option<(u64, u64)>> {
+    let Some(mount) = &config.mount else {
+        return Ok(None);
+    };
+    let Some(pid) = crate::get_first_process_by_uid_or_env(
+        uid,
+        &config.user_env,
+        config.user_manager.ignored_exe(),
+    )?
+    else {
+        return Ok(None);
+    };
+    // Processes in the host's mount namespace are skipped, so this is the user's tmpfs.
+    let path: PathBuf = ["/", "proc", &pid.to_string(), "root"].iter().collect();
+    let path = path.join(mount.tmp.trim_start_matches('/'));
+    let stat = statvfs(&path).io("statvfs", &path)?;
+    let block_size = stat.fragment_size() as u64;
+    let size = stat.blocks() as u64 * block_size;
+    let free = stat.blocks_free() as u64 * block_size;
+    Ok(Some((size, size - free)))
+}
+
+#[derive(Default)]
+struct UserMetrics {
+    netns: bool,
+    sessions: Option<usize>,
+    veth: Option<Sample>,
+    tmp: Option<(u64, u64)>,
+    login: Option<LoginStats>,
+}
+
+const METRICS: &[(&str, &str, &str)] = &[
+    (
+        "pam_isolate_netns_present",
+        "gauge",
+        "Whether the user has a network namespace.",
+    ),
+    (
+        "pam_isolate_sessions",
+        "gauge",
+        "Login sessions with processes in the user's network namespace.",
+    ),
+    (
+        "pam_isolate_veth_receive_bytes_total",
+        "counter",
+        "Bytes sent by the user, as received by veth_{uid}_out.",
+    ),
+    (
+        "pam_isolate_veth_transmit_bytes_total",
+        "counter",
+        "Bytes received by the user, as sent by veth_{uid}_out.",
+    ),
+    (
+        "pam_isolate_veth_receive_packets_total",
+        "counter",
+        "Packets sent by the user.",
+    ),
+    (
+        "pam_isolate_veth_transmit_packets_total",
+        "counter",
+        "Packets received by the user.",
+    ),
+    (
+        "pam_isolate_tmp_size_bytes",
+        "gauge",
+        "Size of the user's tmpfs.",
+    ),
+    (
+        "pam_isolate_tmp_used_bytes",
+        "gauge",
+        "Used bytes of the user's tmpfs.",
+    ),
+    (
+        "pam_isolate_setups_total",
+        "counter",
+        "Session setups since boot.",
+    ),
+    (
+        "pam_isolate_setup_failures_total",
+        "counter",
+        "Failed session setups since boot.",
+    ),
+    (
+        "pam_isolate_setup_duration_seconds",
+        "summary",
+        "Time spent setting up sessions since boot.",
+    ),
+    (
+        "pam_isolate_last_setup_duration_seconds",
+        "gauge",
+        "Time the last session setup took.",
+    ),
+];
+
+impl UserMetrics {
+    fn value(&self, metric: &str) -> Option<String> {
+        let veth = self.veth.as_ref();
+        let login = self.login.as_ref();
+        match metric {
+            "pam_isolate_netns_present" => Some(u8::from(self.netns).to_string()),
+            "pam_isolate_sessions" => self.sessions.map(|count| count.to_string()),
+            "pam_isolate_veth_receive_bytes_total" => veth.map(|s| s.rx_bytes.to_string()),
+            "pam_isolate_veth_transmit_bytes_total" => veth.map(|s| s.tx_bytes.to_string()),
+            "pam_isolate_veth_receive_packets_total" => veth.map(|s| s.rx_packets.to_string()),
+            "pam_isolate_veth_transmit_packets_total" => veth.map(|s| s.tx_packets.to_string()),
+            "pam_isolate_tmp_size_bytes" => self.tmp.map(|(size, _)| size.to_string()),
+            "pam_isolate_tmp_used_bytes" => self.tmp.map(|(_, used)| used.to_string()),
+            "pam_isolate_setups_total" => login.map(|l| l.setups.to_string()),
+            "pam_isolate_setup_failures_total" => login.map(|l| l.failures.to_string()),
+            "pam_isolate_setup_duration_seconds_sum" => {
+                login.map(|l| l.setup_seconds_sum.to_string())
+            }
+            "pam_isolate_setup_duration_seconds_count" => login.map(|l| l.setups.to_string()),
+            "pam_isolate_last_setup_duration_seconds" => {
+                login.map(|l| l.last_setup_seconds.to_string())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Renders the state of all isolated users in the Prometheus text format.
+///
+/// Users are the ones with a network namespace or recorded setups. Errors while inspecting
+/// a single user only leave out the affected metrics.
+pub fn render_metrics(rt: &Runtime, config: &Config) -> Result<String> {
+    let mut users: BTreeMap<String, (Uid, UserMetrics)> = BTreeMap::new();
+    for (name, _) in user_namespaces()? {
+        let user = User::from_name(&name).map_err(|errno| Error::UserLookup {
+            user: name.clone(),
+            errno,
+        })?;
+        let Some(user) = user else {
+            continue;
+        };
+        users
+            .entry(name)
+            .or_insert((user.uid, UserMetrics::default()))
+            .1
+            .netns = true;
+    }
+    for (uid, stats) in login_stats()? {
+        let uid = Uid::from_raw(uid);
+        let name = User::from_uid(uid)
+            .map_err(|errno| Error::UserLookup {
+                user: uid.to_string(),
+                errno,
+            })?
+            .map(|user| user.name)
+            .unwrap_or_else(|| uid.to_string());
+        users
+            .entry(name)
+            .or_insert((uid, UserMetrics::default()))
+            .1
+            .login = Some(stats);
+    }
+
+    rt.block_on(async {
+        let (connection, handle, _) = new_connection().syscall("connect to netlink")?;
+        tokio::spawn(connection);
+        for (name, (uid, metrics)) in &mut users {
+            if !metrics.netns {
+                continue;
+            }
+            metrics.veth = read_sample(&handle, &format!("veth_{uid}_out"))
+                .await
+                .inspect_err(|err| log::warn!("Failed to read the counters of {name}: {err:?}"))
+                .ok()
+                .flatten();
+        }
+        Ok::<_, Error>(())
+    })?;
+
+    for (name, (uid, metrics)) in &mut users {
+        if metrics.netns {
+            metrics.sessions = session_count(&netns_path(name))
+                .inspect_err(|err| log::warn!("Failed to count the sessions of {name}: {err:?}"))
+                .ok();
+        }
+        metrics.tmp = tmp_usage(config, *uid)
+            .inspect_err(|err| log::warn!("Failed to stat the tmpfs of {name}: {err:?}"))
+            .ok()
+            .flatten();
+    }
+
+    // Writing to a String can't fail.
+    let mut output = String::new();
+    for (metric, kind, help) in METRICS {
+        let _ = writeln!(output, "# HELP {metric} {help}");
+        let _ = writeln!(output, "# TYPE {metric} {kind}");
+        let suffixes: &[&str] = match *kind {
+            "summary" => &["_sum", "_count"],
+            _ => &[""],
+        };
+        for suffix in suffixes {
+            let sample = format!("{metric}{suffix}");
+            for (name, (uid, metrics)) in &users {
+                if let Some(value) = metrics.value(&sample) {
+                    let _ = writeln!(output, "{sample}{{user=\"{name}\",uid=\"{uid}\"}} {value}");
+                }
+            }
+        }
+    }
+    Ok(output)
+}
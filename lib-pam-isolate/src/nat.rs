@@ -0,0 +1,171 @@
+use std::{
+    io::{ErrorKind, Write},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use nix::unistd::Uid;
+
+use crate::{AddressPair, Error, HostService, IoContext, Isolation, Result, run_path};
+
+/// Every user gets their own nftables table, so their rules can be replaced and removed at once.
+fn table_name(uid: Uid) -> String {
+    format!("pam_isolate_{uid}")
+}
+
+/// Exists while the table of a user does, so hosts without any rules never need nft(8).
+fn marker_path(uid: Uid) -> PathBuf {
+    run_path().join(format!("nat_{uid}"))
+}
+
+fn run_nft(script: &str) -> Result<()> {
+    let mut child = Command::new("nft")
+        .args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .syscall("spawn nft")?;
+    child
+        .stdin
+        .take()
+        .ok_or(Error::Nft("No stdin for nft".to_owned()))?
+        .write_all(script.as_bytes())
+        .syscall("write to nft")?;
+    let output = child.wait_with_output().syscall("wait for nft")?;
+    if !output.status.success() {
+        return Err(Error::Nft(format!(
+            "{}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+fn dnat_rule(daddr: SocketAddr, protocol: &str, target: SocketAddr) -> String {
+    let family = match daddr.ip() {
+        IpAddr::V4(_) => "ip",
+        IpAddr::V6(_) => "ip6",
+    };
+    let target = match target {
+        SocketAddr::V4(target) => target.to_string(),
+        SocketAddr::V6(target) => format!("[{}]:{}", target.ip(), target.port()),
+    };
+    format!(
+        "{family} daddr {} {protocol} dport {} dnat {family} to {target}",
+        daddr.ip(),
+        daddr.port()
+    )
+}
+
+/// Whether the host services need `route_localnet` on the outside interface.
+pub(crate) fn needs_route_localnet(host_services: &[HostService]) -> bool {
+    host_services
+        .iter()
+        .any(|service| service.target().ip().is_loopback())
+}
+
+/// Removes the DNAT rules of a user, if they were set up. It has to be called from the host's
+/// network namespace.
+pub(crate) fn remove_nat(uid: Uid) -> Result<()> {
+    let marker = marker_path(uid);
+    if !marker.exists() {
+        return Ok(());
+    }
+    let table = table_name(uid);
+    match run_nft(&format!("table inet {table}\ndelete table inet {table}\n")) {
+        Err(Error::Syscall { source, .. }) if source.kind() == ErrorKind::NotFound => {}
+        result => result?,
+    }
+    std::fs::remove_file(&marker).io("remove", &marker)
+}
+
+/// Replaces the DNAT rules of a user, or removes them if there are none.
+///
+/// This covers port forwards from the host to the namespace, as well as host services reachable
+/// from inside. It has to be called from the host's network namespace.
+pub(crate) fn setup_nat(
+    uid: Uid,
+    out_name: &str,
+    outside: &AddressPair,
+    inside: &AddressPair,
+    isolation: &Isolation,
+) -> Result<()> {
+    if isolation.port_forwards.is_empty() && isolation.host_services.is_empty() {
+        // Rules of a previous user might still have to be removed.
+        return remove_nat(uid);
+    }
+
+    let table = table_name(uid);
+    // Creating the table first makes deleting it work even if it doesn't exist yet.
+    let mut script = format!("table inet {table}\ndelete table inet {table}\n");
+
+    let forwards = isolation
+        .port_forwards
+        .iter()
+        .map(|forward| {
+            let target = match forward.listen.ip() {
+                IpAddr::V4(_) => SocketAddr::new(IpAddr::V4(inside.v4), forward.port),
+                IpAddr::V6(_) => SocketAddr::new(IpAddr::V6(inside.v6), forward.port),
+            };
+            format!(
+                "        {}\n",
+                dnat_rule(forward.listen, forward.protocol.as_str(), target)
+            )
+        })
+        .collect::<String>();
+    let services = isolation
+        .host_services
+        .iter()
+        .map(|service| {
+            let target = service.target();
+            let daddr = match target.ip() {
+                IpAddr::V4(_) => SocketAddr::new(IpAddr::V4(outside.v4), service.port),
+                IpAddr::V6(_) => SocketAddr::new(IpAddr::V6(outside.v6), service.port),
+            };
+            format!(
+                "        iifname \"{out_name}\" {}\n",
+                dnat_rule(daddr, service.protocol.as_str(), target)
+            )
+        })
+        .collect::<String>();
+    script.push_str(&format!(
+        "table inet {table} {{\n    chain prerouting {{\n        type nat hook prerouting priority dstnat; policy accept;\n{forwards}{services}    }}\n    chain output {{\n        type nat hook output priority -100; policy accept;\n{forwards}    }}\n}}\n"
+    ));
+
+    // Written first, so the table is removed again even if nft fails halfway.
+    let marker = marker_path(uid);
+    std::fs::create_dir_all(run_path()).io("create", run_path())?;
+    std::fs::write(&marker, "").io("write", &marker)?;
+    run_nft(&script)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv4_dnat_rule() {
+        assert_eq!(
+            dnat_rule(
+                "192.168.121.10:8080".parse().unwrap(),
+                "tcp",
+                "100.64.0.2:80".parse().unwrap()
+            ),
+            "ip daddr 192.168.121.10 tcp dport 8080 dnat ip to 100.64.0.2:80"
+        );
+    }
+
+    #[test]
+    fn ipv6_dnat_rule() {
+        assert_eq!(
+            dnat_rule(
+                "[2001:db8::1]:53".parse().unwrap(),
+                "udp",
+                "[fd75:6272:7370::2]:5353".parse().unwrap()
+            ),
+            "ip6 daddr 2001:db8::1 udp dport 53 dnat ip6 to [fd75:6272:7370::2]:5353"
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use std::{os::fd::OwnedFd, path::PathBuf};
+
+use nix::{
+    mount::{MntFlags, umount2},
+    sched::{CloneFlags, setns},
+    unistd::{Uid, unlink},
+};
+use rtnetlink::new_connection;
+
+use crate::{
+    Audit, AuditAction, IoContext, NamespaceKind, NetlinkContext, Result, SessionContext, audit,
+    get_link_index, nat, sockets,
+};
+
+/// A completed setup step which has to be undone if a later one fails.
+enum Step {
+    /// The nftables table of the user was replaced.
+    Nat(Uid),
+    /// The network namespace was created at this path.
+    Netns(PathBuf),
+    /// The veth pair was created, named by its end on the host.
+    Veth(String),
+    /// The proxy for abstract sockets of the user was started.
+    Proxy(Uid),
+    /// The process entered a namespace, `previous` is the one it was in before.
+    Entered {
+        previous: OwnedFd,
+        namespace: CloneFlags,
+    },
+}
+
+/// Records the steps of a setup, to leave no half-configured state behind on errors.
+///
+/// Otherwise, the next login would join a network namespace without addresses or routes.
+#[derive(Default)]
+pub(crate) struct Rollback {
+    steps: Vec<Step>,
+    /// Audit events of the setup, which are only written once it succeeded.
+    events: Vec<(AuditAction, NamespaceKind)>,
+}
+
+impl Rollback {
+    pub(crate) fn nat(&mut self, uid: Uid) {
+        self.steps.push(Step::Nat(uid));
+    }
+
+    pub(crate) fn netns(&mut self, path: PathBuf) {
+        self.steps.push(Step::Netns(path));
+    }
+
+    pub(crate) fn veth(&mut self, out_name: String) {
+        self.steps.push(Step::Veth(out_name));
+    }
+
+    pub(crate) fn proxy(&mut self, uid: Uid) {
+        self.steps.push(Step::Proxy(uid));
+    }
+
+    pub(crate) fn entered(&mut self, previous: OwnedFd, namespace: CloneFlags) {
+        self.steps.push(Step::Entered {
+            previous,
+            namespace,
+        });
+    }
+
+    pub(crate) fn audit(&mut self, action: AuditAction, namespace: NamespaceKind) {
+        self.events.push((action, namespace));
+    }
+
+    /// The setup succeeded, nothing needs to be undone. Writes its audit events.
+    pub(crate) fn commit(self, audit: Option<&Audit>, session: &SessionContext) {
+        for (action, namespace) in self.events {
+            audit::audit(audit, session, action, namespace);
+        }
+    }
+
+    /// Undoes all recorded steps. Errors are logged, as there is nothing else left to do.
+    ///
+    /// The audit events are dropped, as the namespaces they are about are gone again.
+    ///
+    /// The process first returns to its previous namespaces, so everything it created can be
+    /// removed from the host, in reverse order.
+    pub(crate) async fn undo(self) {
+        let (entered, created): (Vec<_>, Vec<_>) = self
+            .steps
+            .into_iter()
+            .rev()
+            .partition(|step| matches!(step, Step::Entered { .. }));
+
+        for step in entered.into_iter().chain(created) {
+            let result = match &step {
+                Step::Entered {
+                    previous,
+                    namespace,
+                } => setns(previous, *namespace).syscall("setns back to the previous namespace"),
+                Step::Veth(out_name) => delete_veth(out_name).await,
+                Step::Netns(path) => umount2(path, MntFlags::MNT_DETACH)
+                    .io("umount", path)
+                    .and_then(|_| unlink(path).io("unlink", path)),
+                Step::Nat(uid) => nat::remove_nat(*uid),
+                Step::Proxy(uid) => sockets::stop_abstract_proxy(*uid),
+            };
+            match result {
+                Ok(()) => log::info!("[pam_isolate] Rolled back {}", step.describe()),
+                Err(err) => log::error!(
+                    "[pam_isolate] Failed to roll back {}: {err}",
+                    step.describe()
+                ),
+            }
+        }
+    }
+}
+
+impl Step {
+    fn describe(&self) -> String {
+        match self {
+            Step::Nat(uid) => format!("the nftables table of {uid}"),
+            Step::Netns(path) => format!("network namespace {path:?}"),
+            Step::Veth(out_name) => format!("interface {out_name}"),
+            Step::Proxy(uid) => format!("the abstract socket proxy of {uid}"),
+            Step::Entered { namespace, .. } if *namespace == CloneFlags::CLONE_NEWNS => {
+                "entering the mount namespace".to_owned()
+            }
+            Step::Entered { .. } => "entering the network namespace".to_owned(),
+        }
+    }
+}
+
+async fn delete_veth(out_name: &str) -> Result<()> {
+    let (connection, handle, _) = new_connection().syscall("connect to netlink")?;
+    tokio::spawn(connection);
+    if let Some(index) = get_link_index(&handle, out_name).await? {
+        handle
+            .link()
+            .del(index)
+            .execute()
+            .await
+            .netlink("delete", out_name)?;
+    }
+    Ok(())
+}
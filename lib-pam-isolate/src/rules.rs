@@ -0,0 +1,227 @@
+use std::{ffi::CString, net::IpAddr};
+
+use nix::{
+    errno::Errno,
+    unistd::{Gid, Group, Uid, User, getgrouplist},
+};
+
+use crate::{Config, Error, FailureAction, FailurePolicy, Result, Rule, RuleAction};
+
+/// Everything known about a session that rules can match on.
+#[derive(Debug, Clone)]
+pub struct SessionContext {
+    pub username: String,
+    pub uid: Uid,
+    /// The primary group followed by all supplementary groups.
+    pub groups: Vec<Gid>,
+    /// The PAM service, or `"wrapns"`.
+    pub service: String,
+    pub rhost: Option<String>,
+    pub tty: Option<String>,
+}
+
+impl SessionContext {
+    pub fn new(user: &User, service: &str) -> Result<Self> {
+        let lookup_error = |errno| Error::UserLookup {
+            user: user.name.clone(),
+            errno,
+        };
+        let name = CString::new(user.name.as_bytes()).map_err(|_| lookup_error(Errno::EINVAL))?;
+        let mut groups = getgrouplist(&name, user.gid).map_err(lookup_error)?;
+        // getgrouplist() includes the primary group, but it doesn't hurt making sure it comes first.
+        groups.retain(|&gid| gid != user.gid);
+        groups.insert(0, user.gid);
+
+        Ok(SessionContext {
+            username: user.name.clone(),
+            uid: user.uid,
+            groups,
+            service: service.to_owned(),
+            rhost: None,
+            tty: None,
+        })
+    }
+
+    pub fn with_rhost(mut self, rhost: Option<String>) -> Self {
+        self.rhost = rhost.filter(|rhost| !rhost.is_empty());
+        self
+    }
+
+    pub fn with_tty(mut self, tty: Option<String>) -> Self {
+        self.tty = tty.filter(|tty| !tty.is_empty());
+        self
+    }
+}
+
+/// Whether and how a session should be isolated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    Skip,
+    Isolate { profile: Option<String> },
+}
+
+pub(crate) fn in_any_group(names: &[String], groups: &[Gid]) -> bool {
+    names.iter().any(|name| match Group::from_name(name) {
+        Ok(Some(group)) => groups.contains(&group.gid),
+        Ok(None) => false,
+        Err(err) => {
+            log::warn!("[pam_isolate] Failed to look up group {name}: {err}");
+            false
+        }
+    })
+}
+
+fn matches_tty(pattern: &str, tty: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => tty.starts_with(prefix),
+        None => pattern == tty,
+    }
+}
+
+impl Rule {
+    /// All given criteria have to match, an empty rule matches every session.
+    pub fn matches(&self, session: &SessionContext) -> bool {
+        if !self.users.is_empty() && !self.users.contains(&session.username) {
+            return false;
+        }
+        if !self.services.is_empty() && !self.services.contains(&session.service) {
+            return false;
+        }
+        if let Some(uids) = &self.uids
+            && !(uids.min..=uids.max).contains(&session.uid.as_raw())
+        {
+            return false;
+        }
+        if !self.groups.is_empty() && !in_any_group(&self.groups, &session.groups) {
+            return false;
+        }
+        if !self.rhosts.is_empty() {
+            let Some(rhost) = session
+                .rhost
+                .as_deref()
+                .and_then(|rhost| rhost.parse::<IpAddr>().ok())
+            else {
+                return false;
+            };
+            if !self.rhosts.iter().any(|net| net.contains(&rhost)) {
+                return false;
+            }
+        }
+        if !self.ttys.is_empty() {
+            let Some(tty) = &session.tty else {
+                return false;
+            };
+            if !self.ttys.iter().any(|pattern| matches_tty(pattern, tty)) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl FailurePolicy {
+    /// Whether a session of a user with the given groups may be opened despite an error.
+    pub fn allows(&self, groups: &[Gid]) -> bool {
+        self.action == FailureAction::Allow || in_any_group(&self.allow_groups, groups)
+    }
+}
+
+impl Config {
+    /// Decides whether to isolate a session, using the first matching rule.
+    ///
+    /// `users.ignore` is checked before any rule. `requested_profile` is used if given, then the
+    /// profile of the rule, and after that the profile selected by the PAM service.
+    pub fn decide(&self, session: &SessionContext, requested_profile: Option<&str>) -> Decision {
+        if self.users.ignore.contains(&session.username) {
+            log::debug!("[pam_isolate] Ignored user {}.", session.username);
+            return Decision::Skip;
+        }
+
+        let rule = self
+            .rules
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| rule.matches(session));
+        let profile = match rule {
+            Some((index, rule)) if rule.action == RuleAction::Skip => {
+                log::debug!(
+                    "[pam_isolate] Rule #{index} skips isolation of {}.",
+                    session.username
+                );
+                return Decision::Skip;
+            }
+            Some((index, rule)) => {
+                log::debug!("[pam_isolate] Rule #{index} isolates {}.", session.username);
+                rule.profile.as_deref()
+            }
+            None => None,
+        };
+
+        Decision::Isolate {
+            profile: requested_profile
+                .or(profile)
+                .or_else(|| self.profile_for_service(&session.service))
+                .map(str::to_owned),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(rhost: Option<&str>, tty: Option<&str>) -> SessionContext {
+        SessionContext {
+            username: "alice".to_owned(),
+            uid: Uid::from_raw(1000),
+            groups: Vec::new(),
+            service: "sshd".to_owned(),
+            rhost: None,
+            tty: None,
+        }
+        .with_rhost(rhost.map(str::to_owned))
+        .with_tty(tty.map(str::to_owned))
+    }
+
+    fn rule(toml: &str) -> Rule {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn tty_patterns() {
+        assert!(matches_tty("tty1", "tty1"));
+        assert!(!matches_tty("tty1", "tty10"));
+        assert!(matches_tty("tty*", "tty10"));
+        assert!(matches_tty("/dev/pts/*", "/dev/pts/3"));
+        assert!(!matches_tty("tty*", "/dev/tty1"));
+        assert!(matches_tty("*", "pts/0"));
+    }
+
+    #[test]
+    fn rhost_networks() {
+        let rule = rule(
+            r#"action = "skip"
+rhosts = ["10.0.0.0/8", "fd00::/8"]"#,
+        );
+        assert!(rule.matches(&session(Some("10.1.2.3"), None)));
+        assert!(rule.matches(&session(Some("fd12::1"), None)));
+        assert!(!rule.matches(&session(Some("192.168.0.1"), None)));
+        assert!(!rule.matches(&session(Some("fe80::1"), None)));
+        // Host names and sessions without a remote host never match.
+        assert!(!rule.matches(&session(Some("host.example.com"), None)));
+        assert!(!rule.matches(&session(None, None)));
+        assert!(!rule.matches(&session(Some(""), None)));
+    }
+
+    #[test]
+    fn all_criteria_have_to_match() {
+        let rule = rule(
+            r#"action = "skip"
+rhosts = ["10.0.0.0/8"]
+ttys = ["pts/*"]"#,
+        );
+        assert!(rule.matches(&session(Some("10.0.0.1"), Some("pts/1"))));
+        assert!(!rule.matches(&session(Some("10.0.0.1"), Some("tty1"))));
+        assert!(!rule.matches(&session(Some("10.0.0.1"), None)));
+    }
+}
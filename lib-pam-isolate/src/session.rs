@@ -0,0 +1,187 @@
+use std::{os::fd::OwnedFd, path::PathBuf, time::Instant};
+
+use nix::{
+    fcntl::{OFlag, open},
+    sched::{CloneFlags, setns},
+    sys::stat::Mode,
+    unistd::{Gid, Uid, User},
+};
+use tokio::runtime::Runtime;
+
+use crate::{
+    Config, Decision, Env, Error, IoContext, Isolation, Result, SessionContext, SessionInfo,
+    create_namespaces, record_login, reset_log_context, update_log_context,
+};
+
+/// Decides how to isolate a session of a user and resolves its settings.
+///
+/// [`IsolationBuilder::build`] returns a [`Session`], whose namespaces are created or joined by
+/// [`Session::enter`].
+pub struct IsolationBuilder<'a> {
+    config: &'a Config,
+    context: SessionContext,
+    gid: Gid,
+    profile: Option<String>,
+    started: Instant,
+}
+
+impl<'a> IsolationBuilder<'a> {
+    /// `service` is the PAM service, or the name of the tool opening the session.
+    pub fn new(config: &'a Config, user: &User, service: &str) -> Result<Self> {
+        reset_log_context();
+        update_log_context(|context| {
+            context.user = Some(user.name.clone());
+            context.uid = Some(user.uid);
+            context.service = Some(service.to_owned());
+        });
+        Ok(IsolationBuilder {
+            config,
+            context: SessionContext::new(user, service)?,
+            gid: user.gid,
+            profile: None,
+            started: Instant::now(),
+        })
+    }
+
+    pub fn rhost(mut self, rhost: Option<String>) -> Self {
+        self.context = self.context.with_rhost(rhost);
+        self
+    }
+
+    pub fn tty(mut self, tty: Option<String>) -> Self {
+        self.context = self.context.with_tty(tty);
+        self
+    }
+
+    /// The group owning the tmpfs and running the abstract socket proxy, by default the user's
+    /// primary group.
+    pub fn gid(mut self, gid: Gid) -> Self {
+        self.gid = gid;
+        self
+    }
+
+    /// Uses this profile, even if a rule selects another one.
+    pub fn profile(mut self, profile: Option<String>) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    pub fn context(&self) -> &SessionContext {
+        &self.context
+    }
+
+    /// Returns `None` if the session isn't isolated.
+    pub fn build(self) -> Result<Option<Session>> {
+        let profile = match self.config.decide(&self.context, self.profile.as_deref()) {
+            Decision::Skip => return Ok(None),
+            Decision::Isolate { profile } => profile,
+        };
+        let isolation = match self.config.isolation(&self.context, profile.as_deref()) {
+            Ok(isolation) => isolation,
+            Err(err) => {
+                record_setup(self.context.uid, self.started, false);
+                return Err(err);
+            }
+        };
+        log::debug!(
+            "[pam_isolate] Using profile {profile:?} for service {}.",
+            self.context.service
+        );
+
+        Ok(Some(Session {
+            context: self.context,
+            gid: self.gid,
+            isolation,
+            user_env: self.config.user_env.clone(),
+            loopback: self.config.net.loopback.clone(),
+            ignored_exe: self.config.user_manager.ignored_exe().map(PathBuf::from),
+            env: self.config.env.clone(),
+            info: None,
+            previous: None,
+            started: self.started,
+        }))
+    }
+}
+
+/// Counts a session setup for the metrics, from building the session on.
+fn record_setup(uid: Uid, started: Instant, success: bool) {
+    if let Err(err) = record_login(uid, started.elapsed(), success) {
+        log::warn!("[pam_isolate] Failed to record the login metrics: {err:?}");
+    }
+}
+
+/// The namespaces the process was in before entering a session.
+struct PreviousNamespaces {
+    net: OwnedFd,
+    mnt: OwnedFd,
+}
+
+/// An isolated session of a user, built by [`IsolationBuilder`].
+pub struct Session {
+    pub(crate) context: SessionContext,
+    pub(crate) gid: Gid,
+    pub(crate) isolation: Isolation,
+    pub(crate) user_env: String,
+    pub(crate) loopback: String,
+    pub(crate) ignored_exe: Option<PathBuf>,
+    pub(crate) env: Env,
+    info: Option<SessionInfo>,
+    previous: Option<PreviousNamespaces>,
+    started: Instant,
+}
+
+impl Session {
+    pub fn context(&self) -> &SessionContext {
+        &self.context
+    }
+
+    pub fn isolation(&self) -> &Isolation {
+        &self.isolation
+    }
+
+    /// What was created and joined, `None` until the session was entered.
+    pub fn info(&self) -> Option<&SessionInfo> {
+        self.info.as_ref()
+    }
+
+    /// Creates or joins the namespaces of the user and moves the calling process into them.
+    ///
+    /// The sysctls are applied when the network namespace is created.
+    ///
+    /// `set_env` is called with the variables describing the session. This needs root, and the
+    /// process must be single-threaded for the mount namespace.
+    pub fn enter(&mut self, rt: &Runtime, set_env: impl Fn(&str, &str)) -> Result<&SessionInfo> {
+        if self.info.is_some() {
+            return Err(Error::AlreadyEntered);
+        }
+        let (net, mnt) = ("/proc/thread-self/ns/net", "/proc/thread-self/ns/mnt");
+        let result = (|| {
+            self.previous = Some(PreviousNamespaces {
+                net: open(net, OFlag::O_RDONLY, Mode::empty()).io("open", net)?,
+                mnt: open(mnt, OFlag::O_RDONLY, Mode::empty()).io("open", mnt)?,
+            });
+            create_namespaces(rt, self, set_env)
+        })();
+        record_setup(self.context.uid, self.started, result.is_ok());
+
+        Ok(self.info.insert(result?))
+    }
+
+    /// Moves the calling process back into the namespaces it was in before [`Session::enter`].
+    ///
+    /// The user's namespaces are kept for their other sessions. Without calling this, the process
+    /// stays inside of them, which is what a PAM session wants.
+    pub fn release(mut self) -> Result<()> {
+        if let Some(previous) = self.previous.take() {
+            setns(previous.mnt, CloneFlags::CLONE_NEWNS)
+                .syscall("setns back to the previous mount namespace")?;
+            setns(previous.net, CloneFlags::CLONE_NEWNET)
+                .syscall("setns back to the previous network namespace")?;
+            log::debug!(
+                "[pam_isolate] Left the namespaces of {}",
+                self.context.username
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,345 @@
+use std::{
+    ffi::CString,
+    fs::{File, read_dir, read_to_string},
+    io::{ErrorKind, Read, Write},
+    os::{
+        fd::{AsFd, AsRawFd, OwnedFd, RawFd},
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    thread,
+};
+
+use log::LevelFilter;
+use nix::{
+    fcntl::{OFlag, open},
+    mount::{MsFlags, mount},
+    sched::{CloneFlags, setns},
+    sys::{
+        signal::{Signal, kill},
+        stat::Mode,
+        wait::waitpid,
+    },
+    unistd::{
+        ForkResult, Gid, Pid, Uid, dup2_stderr, dup2_stdin, dup2_stdout, fork, pipe, setgid,
+        setgroups, setsid, setuid,
+    },
+};
+
+use crate::{Error, IoContext, Result, SocketBind, run_path};
+
+/// Opens the source directories of the socket binds.
+///
+/// This has to happen before the tmpfs is mounted, as the sockets might live below it.
+pub(crate) fn open_socket_dirs(binds: &[SocketBind]) -> Result<Vec<(OwnedFd, &SocketBind)>> {
+    binds
+        .iter()
+        .map(|bind| {
+            if let Some(name) = bind.allow.iter().find(|name| name.contains('/')) {
+                return Err(Error::config(format!(
+                    "Invalid socket name {name:?} for {:?}",
+                    bind.source
+                )));
+            }
+            let fd = open(
+                &bind.source,
+                OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+                Mode::empty(),
+            )
+            .io("open", &bind.source)?;
+            Ok((fd, bind))
+        })
+        .collect()
+}
+
+fn bind_read_only(source: &Path, target: &Path) -> Result<()> {
+    let mount_error = |operation| {
+        move |errno| Error::Mount {
+            operation,
+            path: target.to_owned(),
+            errno,
+        }
+    };
+    mount(
+        Some(source),
+        target,
+        None::<&str>,
+        MsFlags::MS_BIND,
+        None::<&str>,
+    )
+    .map_err(mount_error("bind mount"))?;
+    mount(
+        None::<&str>,
+        target,
+        None::<&str>,
+        MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+        None::<&str>,
+    )
+    .map_err(mount_error("read-only remount"))?;
+    Ok(())
+}
+
+/// Bind mounts the sockets into the current mount namespace, read-only.
+///
+/// Without an allowlist the whole directory is bound, which keeps working when the service
+/// re-creates its socket. Single sockets have to be bound again after that, so they are only
+/// picked up by new mount namespaces.
+pub(crate) fn bind_sockets(dirs: &[(OwnedFd, &SocketBind)]) -> Result<()> {
+    for (fd, bind) in dirs {
+        let source = PathBuf::from(format!("/proc/self/fd/{}", fd.as_raw_fd()));
+        let target = bind.target.as_ref().unwrap_or(&bind.source);
+        std::fs::create_dir_all(target).io("create", target)?;
+
+        if bind.allow.is_empty() {
+            bind_read_only(&source, target)?;
+            log::info!("[pam_isolate] Bound {:?} to {target:?}", bind.source);
+            continue;
+        }
+
+        for name in &bind.allow {
+            let source = source.join(name);
+            let target = target.join(name);
+            if !source.exists() {
+                log::warn!(
+                    "[pam_isolate] Socket {name} doesn't exist in {:?}, skipping",
+                    bind.source
+                );
+                continue;
+            }
+            if let Err(err) = File::create_new(&target)
+                && err.kind() != ErrorKind::AlreadyExists
+            {
+                return Err(err).io("create", &target);
+            }
+            bind_read_only(&source, &target)?;
+            log::info!("[pam_isolate] Bound socket {name} to {target:?}");
+        }
+    }
+    Ok(())
+}
+
+fn close_fds_except(keep: &[RawFd]) -> Result<()> {
+    let fds = read_dir("/proc/self/fd")
+        .io("read", "/proc/self/fd")?
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<RawFd>().ok())
+        .filter(|fd| *fd > 2 && !keep.contains(fd))
+        .collect::<Vec<_>>();
+    for fd in fds {
+        // The fd of the directory listing itself is gone already.
+        unsafe { libc::close(fd) };
+    }
+    Ok(())
+}
+
+fn proxy_connection(client: UnixStream, name: &str) -> std::io::Result<()> {
+    let server = UnixStream::connect_addr(&SocketAddr::from_abstract_name(name)?)?;
+    let (mut client_read, mut server_write) = (client.try_clone()?, server.try_clone()?);
+    let upstream = thread::spawn(move || {
+        let _ = std::io::copy(&mut client_read, &mut server_write);
+        let _ = server_write.shutdown(std::net::Shutdown::Write);
+    });
+    let (mut server_read, mut client_write) = (server, client);
+    let _ = std::io::copy(&mut server_read, &mut client_write);
+    let _ = client_write.shutdown(std::net::Shutdown::Write);
+    let _ = upstream.join();
+    Ok(())
+}
+
+/// Logs from the daemon, which can't use the logger, as its socket is closed.
+fn syslog_warning(message: &str) {
+    if let Ok(message) = CString::new(format!("[pam_isolate] {message}")) {
+        unsafe {
+            libc::syslog(
+                libc::LOG_DAEMON | libc::LOG_WARNING,
+                c"%s".as_ptr(),
+                message.as_ptr(),
+            )
+        };
+    }
+}
+
+/// Holds the PID and start time of the proxy of a user.
+fn proxy_pid_path(uid: Uid) -> PathBuf {
+    run_path().join(format!("proxy_{uid}.pid"))
+}
+
+/// When a process started, in clock ticks since boot, which tells it apart from later ones with
+/// the same PID.
+fn start_time(pid: Pid) -> Option<u64> {
+    let stat = read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // The command in parentheses might contain spaces, the start time is the 22nd field.
+    stat.rsplit_once(')')?
+        .1
+        .split_whitespace()
+        .nth(19)?
+        .parse()
+        .ok()
+}
+
+fn write_pid_file(uid: Uid, pid: Pid) -> Result<()> {
+    let path = proxy_pid_path(uid);
+    let started = start_time(pid).ok_or(Error::Proxy(format!("{pid} is gone")))?;
+    std::fs::write(&path, format!("{pid} {started}\n")).io("write", &path)
+}
+
+/// Detaches from the session and drops the privileges. Errors are written to `status`.
+fn setup_proxy(
+    listeners: &[(String, UnixListener)],
+    host_netns_fd: &OwnedFd,
+    uid: Uid,
+    gid: Gid,
+    status: &File,
+) -> Result<()> {
+    setsid().syscall("setsid")?;
+    if let ForkResult::Parent { child } = unsafe { fork() }.syscall("fork")? {
+        // Written from here, as root, so the daemon can be stopped with its namespace.
+        let code = match write_pid_file(uid, child) {
+            Ok(()) => 0,
+            Err(err) => {
+                let _ = kill(child, Signal::SIGKILL);
+                let _ = write!(&*status, "{err}");
+                1
+            }
+        };
+        unsafe { libc::_exit(code) };
+    }
+
+    let mut keep = listeners
+        .iter()
+        .map(|(_, listener)| listener.as_raw_fd())
+        .collect::<Vec<_>>();
+    keep.extend([host_netns_fd.as_raw_fd(), status.as_raw_fd()]);
+    close_fds_except(&keep)?;
+    let null = File::options()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .io("open", "/dev/null")?;
+    dup2_stdin(&null).io("dup2", "/dev/null")?;
+    dup2_stdout(&null).io("dup2", "/dev/null")?;
+    dup2_stderr(&null).io("dup2", "/dev/null")?;
+
+    // The listeners stay in the user's namespace, new connections to the host are made from here.
+    setns(host_netns_fd.as_fd(), CloneFlags::CLONE_NEWNET)
+        .syscall("setns to the host network namespace")?;
+    setgroups(&[gid]).syscall(format!("setgroups to {gid}"))?;
+    setgid(gid).syscall(format!("setgid to {gid}"))?;
+    setuid(uid).syscall(format!("setuid to {uid}"))?;
+    Ok(())
+}
+
+fn run_proxy(
+    listeners: Vec<(String, UnixListener)>,
+    host_netns_fd: &OwnedFd,
+    uid: Uid,
+    gid: Gid,
+    status: File,
+) -> ! {
+    if let Err(err) = setup_proxy(&listeners, host_netns_fd, uid, gid, &status) {
+        let _ = write!(&status, "{err}");
+        unsafe { libc::_exit(1) };
+    }
+    // Closing it tells the session that the daemon is running.
+    drop(status);
+
+    let threads = listeners
+        .into_iter()
+        .map(|(name, listener)| {
+            thread::spawn(move || {
+                for client in listener.incoming().flatten() {
+                    let name = name.clone();
+                    thread::spawn(move || {
+                        if let Err(err) = proxy_connection(client, &name) {
+                            syslog_warning(&format!(
+                                "Failed to proxy a connection to @{name}: {err}"
+                            ));
+                        }
+                    });
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        let _ = thread.join();
+    }
+    unsafe { libc::_exit(0) }
+}
+
+/// Starts a daemon forwarding connections to abstract sockets inside of the user's network
+/// namespace to the ones with the same name on the host.
+///
+/// This has to be called from the user's network namespace. The daemon runs as the user, so the
+/// services see the user's credentials. It is stopped by [`stop_abstract_proxy`].
+pub(crate) fn spawn_abstract_proxy(
+    names: &[String],
+    host_netns_fd: &OwnedFd,
+    uid: Uid,
+    gid: Gid,
+) -> Result<()> {
+    if names.is_empty() {
+        return Ok(());
+    }
+
+    let listeners = names
+        .iter()
+        .map(|name| {
+            let listener = SocketAddr::from_abstract_name(name)
+                .and_then(|addr| UnixListener::bind_addr(&addr))
+                .syscall(format!("bind @{name}"))?;
+            Ok((name.clone(), listener))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let (status_read, status_write) = pipe().syscall("pipe")?;
+
+    match unsafe { fork() }.syscall("fork")? {
+        ForkResult::Parent { child } => {
+            drop(status_write);
+            waitpid(child, None).syscall(format!("wait for {child}"))?;
+            // Empty once the daemon is set up, otherwise it holds the error.
+            let mut status = String::new();
+            File::from(status_read)
+                .read_to_string(&mut status)
+                .syscall("read the status of the proxy")?;
+            if !status.is_empty() {
+                return Err(Error::Proxy(status));
+            }
+            log::info!(
+                "[pam_isolate] Started proxy for abstract sockets {}",
+                names.join(", ")
+            );
+            Ok(())
+        }
+        ForkResult::Child => {
+            // The logger's socket is closed in the daemon, and its fd number might get reused.
+            log::set_max_level(LevelFilter::Off);
+            drop(status_read);
+            run_proxy(listeners, host_netns_fd, uid, gid, File::from(status_write));
+        }
+    }
+}
+
+/// Stops the proxy of a user, if it is running. Its listeners keep the namespace alive otherwise.
+pub(crate) fn stop_abstract_proxy(uid: Uid) -> Result<()> {
+    let path = proxy_pid_path(uid);
+    let content = match read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).io("read", &path),
+    };
+    let proxy = content.trim().split_once(' ').and_then(|(pid, started)| {
+        Some((
+            Pid::from_raw(pid.parse().ok()?),
+            started.parse::<u64>().ok()?,
+        ))
+    });
+    // The PID might belong to another process by now.
+    if let Some((pid, started)) = proxy
+        && start_time(pid) == Some(started)
+    {
+        kill(pid, Signal::SIGTERM).syscall(format!("kill {pid}"))?;
+        log::info!("[pam_isolate] Stopped the abstract socket proxy {pid}");
+    }
+    std::fs::remove_file(&path).io("remove", &path)
+}
@@ -0,0 +1,234 @@
+use std::{
+    collections::HashMap,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use nix::{
+    sched::{CloneFlags, unshare},
+    unistd::{Group, User},
+};
+use sysctl::{Ctl, Sysctl};
+
+use crate::{Config, SysctlValue};
+
+type SysctlTable = HashMap<String, SysctlValue>;
+
+/// Checks that a sysctl exists on this host and that its value fits the current one.
+fn check_sysctl(key: &str, value: &SysctlValue) -> Option<String> {
+    let current = match Ctl::new(key).and_then(|ctl| ctl.value_string()) {
+        Ok(current) => current,
+        Err(err) => return Some(format!("sysctl \"{key}\" doesn't exist: {err}")),
+    };
+    let fields = current.split_whitespace().collect::<Vec<_>>();
+    // Linux only knows text, but the number of values and whether they are numbers has to fit.
+    let fits = match value {
+        SysctlValue::String(_) => true,
+        SysctlValue::Integer(_) => fields.len() == 1 && fields[0].parse::<i64>().is_ok(),
+        SysctlValue::Array(values) => values.len() == fields.len(),
+    };
+    (!fits).then(|| {
+        format!("sysctl \"{key}\" can't be set to {value:?}, its current value is {current:?}")
+    })
+}
+
+/// Like [`check_sysctl`], in the current network namespace, which has to be a new one.
+///
+/// Some `net.*` sysctls are global, and either missing or read-only in other namespaces.
+fn check_netns_sysctl(key: &str, value: &SysctlValue) -> Option<String> {
+    let path: PathBuf = ["/", "proc", "sys", &key.replace('.', "/")]
+        .iter()
+        .collect();
+    match std::fs::metadata(&path) {
+        Ok(metadata) if metadata.permissions().mode() & 0o200 == 0 => Some(format!(
+            "sysctl \"{key}\" is read-only in network namespaces, it can only be set on the host"
+        )),
+        Ok(_) => check_sysctl(key, value),
+        Err(_) => Some(format!(
+            "sysctl \"{key}\" doesn't exist in network namespaces, it is global or unknown"
+        )),
+    }
+}
+
+/// Checks the sysctls of the tables in a freshly unshared network namespace, like the ones of
+/// the sessions.
+fn check_netns_sysctls(tables: &[(String, SysctlTable)]) -> nix::Result<Vec<String>> {
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                unshare(CloneFlags::CLONE_NEWNET)?;
+                Ok(check_tables(tables, check_netns_sysctl))
+            })
+            .join()
+            .unwrap_or(Err(nix::Error::EINVAL))
+    })
+}
+
+fn check_tables(
+    tables: &[(String, SysctlTable)],
+    check: fn(&str, &SysctlValue) -> Option<String>,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (name, table) in tables {
+        let mut keys = table.keys().collect::<Vec<_>>();
+        keys.sort();
+        for key in keys {
+            if let Some(problem) = check(key, &table[key]) {
+                problems.push(format!("{name}]: {problem}"));
+            }
+        }
+    }
+    problems
+}
+
+impl Config {
+    /// Checks what parsing can't: that sysctls, users, groups and profiles exist.
+    ///
+    /// Returns all problems found, as messages. Sysctls are checked against the host, the ones
+    /// of the network namespaces in a new one. That needs root.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        let mut users = HashMap::new();
+        let mut groups = HashMap::new();
+
+        let sysctls = std::iter::once(("[sysctl".to_owned(), &self.sysctl)).chain(
+            self.profiles.iter().filter_map(|(name, profile)| {
+                Some((format!("[profiles.{name}.sysctl"), profile.sysctl.as_ref()?))
+            }),
+        );
+        let (mut netns_tables, mut host_tables) = (Vec::new(), Vec::new());
+        for (section, sysctl) in sysctls {
+            users.extend(sysctl.users.keys().map(|user| (user, "sysctl.users")));
+            groups.extend(sysctl.groups.keys().map(|group| (group, "sysctl.groups")));
+            for key in &sysctl.legacy_keys {
+                problems.push(format!(
+                    "{section}]: \"{key}\" is deprecated here, move it to {section}.netns]"
+                ));
+            }
+            netns_tables.push((format!("{section}.netns"), sysctl.netns.clone()));
+            for (group, table) in &sysctl.groups {
+                netns_tables.push((format!("{section}.groups.{group}"), table.clone()));
+            }
+            for (user, table) in &sysctl.users {
+                netns_tables.push((format!("{section}.users.{user}"), table.clone()));
+            }
+            // The interface doesn't exist yet, but all of them have the same sysctls.
+            host_tables.push((
+                format!("{section}.host_iface"),
+                sysctl.host_iface_sysctls("default"),
+            ));
+        }
+        match check_netns_sysctls(&netns_tables) {
+            Ok(netns_problems) => problems.extend(netns_problems),
+            Err(err) => {
+                problems.push(format!(
+                    "sysctl: failed to check the sysctls in a new network namespace: {err}"
+                ));
+                problems.extend(check_tables(&netns_tables, check_sysctl));
+            }
+        }
+        problems.extend(check_tables(&host_tables, check_sysctl));
+
+        users.extend(self.users.ignore.iter().map(|user| (user, "users.ignore")));
+        users.extend(
+            self.net
+                .port_forwards
+                .iter()
+                .map(|forward| (&forward.user, "net.port_forwards")),
+        );
+        for rule in &self.rules {
+            users.extend(rule.users.iter().map(|user| (user, "rules")));
+            groups.extend(rule.groups.iter().map(|group| (group, "rules")));
+        }
+        for profile in self.profiles.values() {
+            if let Some(on_failure) = &profile.on_failure {
+                groups.extend(
+                    on_failure
+                        .allow_groups
+                        .iter()
+                        .map(|group| (group, "on_failure.allow_groups")),
+                );
+            }
+        }
+        let mut users = users.into_iter().collect::<Vec<_>>();
+        users.sort();
+        for (user, section) in users {
+            match User::from_name(user) {
+                Ok(Some(_)) => {}
+                Ok(None) => problems.push(format!("{section}: unknown user {user:?}")),
+                Err(err) => problems.push(format!("{section}: failed to look up {user:?}: {err}")),
+            }
+        }
+        let mut groups = groups.into_iter().collect::<Vec<_>>();
+        groups.sort();
+        for (group, section) in groups {
+            match Group::from_name(group) {
+                Ok(Some(_)) => {}
+                Ok(None) => problems.push(format!("{section}: unknown group {group:?}")),
+                Err(err) => problems.push(format!("{section}: failed to look up {group:?}: {err}")),
+            }
+        }
+
+        for (index, rule) in self.rules.iter().enumerate() {
+            if let Some(profile) = &rule.profile
+                && !self.profiles.contains_key(profile)
+            {
+                problems.push(format!("rules #{index}: unknown profile {profile:?}"));
+            }
+            if let Some(uids) = rule.uids
+                && uids.min > uids.max
+            {
+                problems.push(format!(
+                    "rules #{index}: uids.min {} is greater than uids.max {}",
+                    uids.min, uids.max
+                ));
+            }
+        }
+
+        if self.user_env.contains('=') || self.env.names().any(|name| name.contains('=')) {
+            problems.push("env: variable names must not contain `=`".to_owned());
+        }
+        problems
+    }
+
+    /// Loads and validates the config file with its drop-ins, and then once more with the
+    /// overrides of every user in its `users` directory.
+    ///
+    /// Problems of a user's config which the shared one has as well are only reported once.
+    pub fn validate_files(path: &Path) -> Vec<String> {
+        let mut problems = match Config::load(path) {
+            Ok(config) => config.validate(),
+            // Parsing stops at the first error.
+            Err(err) => return vec![err.to_string()],
+        };
+        let users = match Config::overridden_users(path) {
+            Ok(users) => users,
+            Err(err) => {
+                problems.push(err.to_string());
+                return problems;
+            }
+        };
+        let shared = problems.clone();
+        for user in users {
+            match User::from_name(&user) {
+                Ok(Some(_)) => {}
+                Ok(None) => problems.push(format!("users/{user}.toml: unknown user {user:?}")),
+                Err(err) => problems.push(format!(
+                    "users/{user}.toml: failed to look up {user:?}: {err}"
+                )),
+            }
+            match Config::load_for_user(path, &user) {
+                Ok(config) => problems.extend(
+                    config
+                        .validate()
+                        .into_iter()
+                        .filter(|problem| !shared.contains(problem))
+                        .map(|problem| format!("users/{user}.toml: {problem}")),
+                ),
+                Err(err) => problems.push(err.to_string()),
+            }
+        }
+        problems
+    }
+}
@@ -1,15 +1,23 @@
 use core::slice;
 use std::{
     ffi::{CStr, CString, OsStr, OsString, c_char, c_int},
+    ops::Deref,
     os::unix::prelude::OsStrExt,
     path::PathBuf,
 };
 
 use clap::Parser;
-use lib_pam_isolate::{Config, create_namespaces, try_setup_sysctl};
+use lib_pam_isolate::{
+    Config, ContextLogger, Error, FailureAction, FailurePolicy, IsolationBuilder, SessionContext,
+    reset_log_context, update_log_context,
+};
 use log::LevelFilter;
-use nix::unistd::User;
-use pam::{constants::PamResultCode, module::PamHandle};
+use nix::unistd::{Gid, User};
+use pam::{
+    constants::{PAM_SILENT, PAM_TEXT_INFO, PamFlag, PamResultCode},
+    conv::Conv,
+    module::PamHandle,
+};
 use systemd_journal_logger::JournalLog;
 
 #[derive(Parser, Debug)]
@@ -18,53 +26,157 @@ struct Args {
     config: PathBuf,
     #[arg(short, long, default_value_t = LevelFilter::Warn)]
     log_level: LevelFilter,
+    /// Use this profile instead of the one selected by the PAM service.
+    #[arg(short, long)]
+    profile: Option<String>,
+    /// What to do on errors, unless the profile says otherwise.
+    #[arg(long, default_value = "deny")]
+    on_failure: FailureAction,
+    /// Allow members of this group in even if `--on-failure` is `deny`.
+    #[arg(long)]
+    allow_group: Vec<String>,
 }
 
 unsafe extern "C" {
     unsafe fn pam_putenv(pamh: *const PamHandle, name_value: *const c_char);
 }
 
-fn open_session(args: Args, pamh: &PamHandle) -> anyhow::Result<()> {
-    let config = Config::load(args.config)?;
+/// Errors of `open_session`, each mapped to its own PAM result code.
+#[derive(Debug)]
+enum SessionError {
+    /// The configuration couldn't be loaded or is inconsistent.
+    Config(anyhow::Error),
+    /// Querying PAM or the user database failed.
+    System(anyhow::Error),
+    /// Setting up the namespaces failed.
+    Setup(anyhow::Error),
+}
 
-    let rt = tokio::runtime::Builder::new_current_thread()
-        .enable_all()
-        .build()?;
+impl SessionError {
+    fn code(&self) -> PamResultCode {
+        match self {
+            SessionError::Config(_) => PamResultCode::PAM_SERVICE_ERR,
+            SessionError::System(_) => PamResultCode::PAM_SYSTEM_ERR,
+            SessionError::Setup(_) => PamResultCode::PAM_SESSION_ERR,
+        }
+    }
+}
+
+impl From<Error> for SessionError {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Config { .. } | Error::UnsupportedUid(_) => SessionError::Config(err.into()),
+            Error::UserLookup { .. } => SessionError::System(err.into()),
+            _ => SessionError::Setup(err.into()),
+        }
+    }
+}
+
+impl std::fmt::Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionError::Config(err) => write!(f, "config error: {err:?}"),
+            SessionError::System(err) => write!(f, "system error: {err:?}"),
+            SessionError::Setup(err) => write!(f, "setup error: {err:?}"),
+        }
+    }
+}
+
+/// Decides what happens if `open_session` fails. Filled in as more about the session is known.
+struct FailureContext {
+    policy: FailurePolicy,
+    groups: Vec<Gid>,
+}
+
+fn get_str_item<'a, T>(pamh: &'a PamHandle, name: &str) -> anyhow::Result<Option<String>>
+where
+    T: pam::items::Item + Deref<Target = &'a CStr>,
+{
+    Ok(pamh
+        .get_item::<T>()
+        .map_err(|err| anyhow::anyhow!("get_{name}: {err:?}"))?
+        .map(|item| item.to_string_lossy().into_owned()))
+}
 
+fn send_message(pamh: &PamHandle, message: &str) -> anyhow::Result<()> {
+    let conv = pamh
+        .get_item::<Conv>()
+        .map_err(|err| anyhow::anyhow!("get_conv: {err:?}"))?
+        .ok_or(anyhow::anyhow!("No conversation function"))?;
+    conv.send(PAM_TEXT_INFO, message)
+        .map_err(|err| anyhow::anyhow!("conv: {err:?}"))?;
+    Ok(())
+}
+
+fn open_session(
+    args: Args,
+    pamh: &PamHandle,
+    silent: bool,
+    failure: &mut FailureContext,
+) -> Result<(), SessionError> {
+    reset_log_context();
     let username = pamh
         .get_item::<pam::items::User>()
-        .map_err(|err| anyhow::anyhow!("get_user: {err:?}"))?
-        .ok_or(anyhow::anyhow!("No username"))?;
-    let username = String::from_utf8(username.to_bytes().to_vec())?;
-    if !config.users.ignore.is_empty() && config.users.ignore.contains(&username) {
-        log::debug!("[pam_isolate] Ignored user {username}.");
+        .map_err(|err| SessionError::System(anyhow::anyhow!("get_user: {err:?}")))?
+        .ok_or(SessionError::System(anyhow::anyhow!("No username")))?;
+    let username = String::from_utf8(username.to_bytes().to_vec())
+        .map_err(|err| SessionError::System(err.into()))?;
+    update_log_context(|context| context.user = Some(username.clone()));
+    let passwd = User::from_name(&username).map_err(|err| SessionError::System(err.into()))?;
+    if let Some(passwd) = &passwd {
+        failure.groups = SessionContext::new(passwd, "")
+            .map(|session| session.groups)
+            .unwrap_or_default();
+    }
+
+    let config = Config::load_for_user(args.config, &username)?;
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|err| SessionError::System(err.into()))?;
+
+    let service = get_str_item::<pam::items::Service>(pamh, "service")
+        .map_err(SessionError::System)?
+        .unwrap_or_default();
+    update_log_context(|context| context.service = Some(service.clone()));
+    if service == config.user_manager.service && config.user_manager.skipped() {
+        log::debug!("[pam_isolate] Not isolating the user manager of {username}.");
         return Ok(());
     }
 
-    let Some(passwd) = User::from_name(&username)? else {
+    let Some(passwd) = passwd else {
         log::error!("[pam_isolate] Unknown user name {username}");
         return Ok(());
     };
+    update_log_context(|context| context.uid = Some(passwd.uid));
 
-    create_namespaces(
-        &rt,
-        &username,
-        passwd.uid,
-        passwd.gid,
-        &config.mount,
-        &config.user_env,
-        &config.net.loopback,
-        |key, value| {
-            let s = CString::new(format!("{key}={value}")).unwrap();
-            unsafe {
-                pam_putenv(pamh as *const PamHandle, s.as_ptr());
-                std::env::set_var(key, value);
-            }
-        },
-    )?;
+    let builder = IsolationBuilder::new(&config, &passwd, &service)?
+        .rhost(get_str_item::<pam::items::RHost>(pamh, "rhost").map_err(SessionError::System)?)
+        .tty(get_str_item::<pam::items::Tty>(pamh, "tty").map_err(SessionError::System)?)
+        .profile(args.profile);
+    let Some(mut session) = builder.build()? else {
+        return Ok(());
+    };
+    if let Some(policy) = &session.isolation().on_failure {
+        failure.policy = policy.clone();
+    }
 
-    if !config.sysctl.is_empty() {
-        try_setup_sysctl(&config.sysctl);
+    let info = session.enter(&rt, |key, value| {
+        let s = CString::new(format!("{key}={value}")).unwrap();
+        unsafe {
+            pam_putenv(pamh as *const PamHandle, s.as_ptr());
+            std::env::set_var(key, value);
+        }
+    })?;
+
+    if let Some(template) = &config.login_message
+        && !silent
+    {
+        let message = info.render_message(template, &username);
+        if let Err(err) = send_message(pamh, &message) {
+            log::warn!("[pam_isolate] Failed to send login message: {err:?}");
+        }
     }
 
     log::info!("[pam_isolate] User logged in");
@@ -79,7 +191,7 @@ fn open_session(args: Args, pamh: &PamHandle) -> anyhow::Result<()> {
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn pam_sm_open_session(
     pamh: *mut PamHandle,
-    _flags: c_int,
+    flags: c_int,
     argc: c_int,
     argv: *const *const u8,
 ) -> PamResultCode {
@@ -92,18 +204,33 @@ pub unsafe extern "C" fn pam_sm_open_session(
 
     let args = Args::parse_from(args);
 
-    JournalLog::new()
-        .unwrap()
-        .with_extra_fields(vec![("OBJECT_EXE", "pam_isolate.so")])
-        .install()
-        .unwrap();
+    // The logger stays installed for the following sessions of the same process, and a panic
+    // would abort it.
+    if let Ok(journal) = JournalLog::new() {
+        let journal = journal.with_extra_fields(vec![("OBJECT_EXE", "pam_isolate.so")]);
+        let _ = log::set_boxed_logger(Box::new(ContextLogger(journal)));
+    }
     log::set_max_level(args.log_level);
 
-    match open_session(args, unsafe { &*pamh }) {
+    let mut failure = FailureContext {
+        policy: FailurePolicy {
+            action: args.on_failure,
+            allow_groups: args.allow_group.clone(),
+        },
+        groups: Vec::new(),
+    };
+    let silent = flags as PamFlag & PAM_SILENT != 0;
+    match open_session(args, unsafe { &*pamh }, silent, &mut failure) {
         Ok(()) => PamResultCode::PAM_SUCCESS,
+        Err(err) if failure.policy.allows(&failure.groups) => {
+            log::error!(
+                "[pam_isolate] open_session failed, allowing the session WITHOUT isolation: {err}"
+            );
+            PamResultCode::PAM_SUCCESS
+        }
         Err(err) => {
-            log::error!("[pam_isolate] open_session: {err:?}");
-            PamResultCode::PAM_ABORT
+            log::error!("[pam_isolate] open_session: {err}");
+            err.code()
         }
     }
 }
@@ -1,17 +1,16 @@
 use std::{ffi::CString, os::unix::prelude::OsStrExt};
 
 use anyhow::anyhow;
-use lib_pam_isolate::{Config, create_namespaces, try_setup_sysctl};
+use lib_pam_isolate::{Config, ContextLogger, IsolationBuilder, update_log_context};
 use log::LevelFilter;
-use nix::unistd::{User, execv, getegid, geteuid, getgid, getuid, setgid, setuid};
+use nix::unistd::{User, execv, getegid, geteuid, getgid, getuid, setgid, setuid, ttyname};
 use systemd_journal_logger::JournalLog;
 
 fn main() -> anyhow::Result<()> {
-    JournalLog::new()
+    let journal = JournalLog::new()
         .unwrap()
-        .with_extra_fields(vec![("OBJECT_EXE", "wrapns")])
-        .install()
-        .unwrap();
+        .with_extra_fields(vec![("OBJECT_EXE", "wrapns")]);
+    log::set_boxed_logger(Box::new(ContextLogger(journal))).unwrap();
     log::set_max_level(LevelFilter::Warn);
 
     let args: Vec<_> = std::env::args_os().collect();
@@ -20,8 +19,6 @@ fn main() -> anyhow::Result<()> {
         return Err(anyhow!("Pass a command to execute"));
     }
 
-    let config = Config::load(Config::default_path())?;
-
     let rt = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()?;
@@ -36,28 +33,30 @@ fn main() -> anyhow::Result<()> {
         return Err(anyhow!("Unknown user"));
     };
 
-    if !config.users.ignore.is_empty() && config.users.ignore.contains(&passwd.name) {
-        log::debug!("Ignored user {}.", passwd.name);
+    let config = Config::load_for_user(Config::default_path(), &passwd.name)?;
+
+    update_log_context(|context| {
+        context.user = Some(passwd.name.clone());
+        context.uid = Some(uid);
+        context.service = Some("wrapns".to_owned());
+    });
+
+    let tty = ttyname(std::io::stdin())
+        .ok()
+        .map(|tty| tty.to_string_lossy().into_owned());
+    let Some(mut session) = IsolationBuilder::new(&config, &passwd, "wrapns")?
+        .tty(tty)
+        .gid(gid)
+        .build()?
+    else {
         return Err(anyhow!("Ignored user"));
-    }
+    };
 
     setuid(euid)?;
     setgid(egid)?;
-    create_namespaces(
-        &rt,
-        &passwd.name,
-        uid,
-        gid,
-        &config.mount,
-        &config.user_env,
-        &config.net.loopback,
-        |key, value| unsafe {
-            std::env::set_var(key, value);
-        },
-    )?;
-    if !config.sysctl.is_empty() {
-        try_setup_sysctl(&config.sysctl);
-    }
+    session.enter(&rt, |key, value| unsafe {
+        std::env::set_var(key, value);
+    })?;
     setgid(gid)?;
     setuid(uid)?;
 